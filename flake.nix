@@ -57,7 +57,7 @@
           git # not sure why maelstrom needs this
         ];
 
-        inherit (self.packages.${system}) echo unique broadcast counter replicated-log;
+        inherit (self.packages.${system}) echo unique broadcast counter pn-counter replicated-log txn list-append lin-kv lock;
 
         ci_packages = {
           # Nix
@@ -183,6 +183,22 @@
             '';
           };
 
+          # https://github.com/NixOS/nix/issues/8881
+          # nix build '.#checks.x86_64-linux.pn-counter' --print-build-logs --keep-failed
+          # --keep-failed writes the sandbox directory at /tmp/nix-build-.../build/<hash>-source/
+          # We use `nix build` instead of `nix run` because the check doesn't produce an executable to run.
+          # We use mkDerivation instead of runCommand because we need to set `src`.
+          pn-counter = pkgs.stdenvNoCC.mkDerivation {
+            name = "maelstrom-pn-counter";
+            src = ./.;
+            nativeBuildInputs = maelstromDeps ++ [ pn-counter ];
+            buildPhase = ''
+              echo "===> running 'maelstrom pn-counter' tests"
+              java -Djava.awt.headless=true -jar "./maelstrom.jar" test -w pn-counter --bin ${pn-counter}/bin/pn-counter --node-count 3 --time-limit 20 --rate 100 --nemesis partition
+              mkdir -p $out # required by derivations even though it's empty
+            '';
+          };
+
           # https://github.com/NixOS/nix/issues/8881
           # nix build '.#checks.x86_64-linux.replicated-log' --print-build-logs --keep-failed
           # --keep-failed writes the sandbox directory at /tmp/nix-build-.../build/<hash>-source/
@@ -198,6 +214,56 @@
               mkdir -p $out # required by derivations even though it's empty
             '';
           };
+
+          # https://github.com/NixOS/nix/issues/8881
+          # nix build '.#checks.x86_64-linux.txn' --print-build-logs --keep-failed
+          # --keep-failed writes the sandbox directory at /tmp/nix-build-.../build/<hash>-source/
+          # We use `nix build` instead of `nix run` because the check doesn't produce an executable to run.
+          # We use mkDerivation instead of runCommand because we need to set `src`.
+          txn = pkgs.stdenvNoCC.mkDerivation {
+            name = "maelstrom-txn";
+            src = ./.;
+            nativeBuildInputs = maelstromDeps ++ [ txn ];
+            buildPhase = ''
+              echo "===> running 'maelstrom txn-rw-register' tests"
+              java -Djava.awt.headless=true -jar "./maelstrom.jar" test -w txn-rw-register --bin ${txn}/bin/txn --node-count 2 --concurrency 2n --time-limit 20 --rate 1000 --consistency-models read-committed --availability total --nemesis partition
+              mkdir -p $out # required by derivations even though it's empty
+            '';
+          };
+
+          # https://github.com/NixOS/nix/issues/8881
+          # nix build '.#checks.x86_64-linux.list-append' --print-build-logs --keep-failed
+          # --keep-failed writes the sandbox directory at /tmp/nix-build-.../build/<hash>-source/
+          # We use `nix build` instead of `nix run` because the check doesn't produce an executable to run.
+          # We use mkDerivation instead of runCommand because we need to set `src`.
+          list-append = pkgs.stdenvNoCC.mkDerivation {
+            name = "maelstrom-list-append";
+            src = ./.;
+            nativeBuildInputs = maelstromDeps ++ [ list-append ];
+            buildPhase = ''
+              echo "===> running 'maelstrom txn-list-append' tests"
+              java -Djava.awt.headless=true -jar "./maelstrom.jar" test -w txn-list-append --bin ${list-append}/bin/list-append --node-count 2 --concurrency 2n --time-limit 20 --rate 100 --consistency-models strict-serializable
+              mkdir -p $out # required by derivations even though it's empty
+            '';
+          };
+
+          # https://github.com/NixOS/nix/issues/8881
+          # nix build '.#checks.x86_64-linux.lin-kv' --print-build-logs --keep-failed
+          # --keep-failed writes the sandbox directory at /tmp/nix-build-.../build/<hash>-source/
+          # We use `nix build` instead of `nix run` because the check doesn't produce an executable to run.
+          # We use mkDerivation instead of runCommand because we need to set `src`.
+          lin-kv = pkgs.stdenvNoCC.mkDerivation {
+            name = "maelstrom-lin-kv";
+            src = ./.;
+            nativeBuildInputs = maelstromDeps ++ [ lin-kv ];
+            buildPhase = ''
+              echo "===> running 'maelstrom lin-kv' tests"
+              LIN_KV_DIR="$(mktemp -d)" java -Djava.awt.headless=true -jar "./maelstrom.jar" test -w lin-kv --bin ${lin-kv}/bin/lin-kv --node-count 3 --concurrency 2n --time-limit 20 --rate 100 --nemesis partition,kill
+              echo "===> running 'maelstrom lin-kv' tests with paxos"
+              LIN_KV_CONSENSUS=paxos java -Djava.awt.headless=true -jar "./maelstrom.jar" test -w lin-kv --bin ${lin-kv}/bin/lin-kv --node-count 3 --concurrency 2n --time-limit 20 --rate 100 --nemesis partition
+              mkdir -p $out # required by derivations even though it's empty
+            '';
+          };
         };
 
         packages = {
@@ -254,6 +320,19 @@
             doInstallCheck = false; # disable so that these can be built independently
           };
 
+          # nix build '.#pn-counter'
+          # nix run '.#pn-counter'
+          pn-counter = rustPlatform.buildRustPackage {
+            pname = "pn-counter";
+            version = "1.0.0";
+            src = pkgs.lib.cleanSource ./.; # the folder with the cargo.toml
+            cargoLock.lockFile = ./Cargo.lock;
+            cargoBuildFlags = [ "--bin" "pn-counter" ];
+            doCheck = false; # disable so that these can be built independently
+            # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
+            doInstallCheck = false; # disable so that these can be built independently
+          };
+
           # nix build '.#replicated-log'
           # nix run '.#replicated-log'
           replicated-log = rustPlatform.buildRustPackage {
@@ -266,6 +345,59 @@
             # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
             doInstallCheck = false; # disable so that these can be built independently
           };
+
+          # nix build '.#txn'
+          # nix run '.#txn'
+          txn = rustPlatform.buildRustPackage {
+            pname = "txn";
+            version = "1.0.0";
+            src = pkgs.lib.cleanSource ./.; # the folder with the cargo.toml
+            cargoLock.lockFile = ./Cargo.lock;
+            cargoBuildFlags = [ "--bin" "txn" ];
+            doCheck = false; # disable so that these can be built independently
+            # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
+            doInstallCheck = false; # disable so that these can be built independently
+          };
+
+          # nix build '.#list-append'
+          # nix run '.#list-append'
+          list-append = rustPlatform.buildRustPackage {
+            pname = "list-append";
+            version = "1.0.0";
+            src = pkgs.lib.cleanSource ./.; # the folder with the cargo.toml
+            cargoLock.lockFile = ./Cargo.lock;
+            cargoBuildFlags = [ "--bin" "list-append" ];
+            doCheck = false; # disable so that these can be built independently
+            # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
+            doInstallCheck = false; # disable so that these can be built independently
+          };
+
+          # nix build '.#lin-kv'
+          # nix run '.#lin-kv'
+          lin-kv = rustPlatform.buildRustPackage {
+            pname = "lin-kv";
+            version = "1.0.0";
+            src = pkgs.lib.cleanSource ./.; # the folder with the cargo.toml
+            cargoLock.lockFile = ./Cargo.lock;
+            cargoBuildFlags = [ "--bin" "lin-kv" ];
+            doCheck = false; # disable so that these can be built independently
+            # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
+            doInstallCheck = false; # disable so that these can be built independently
+          };
+
+          # Maelstrom doesn't have a workload for locks, so there's no check for this one.
+          # nix build '.#lock'
+          # nix run '.#lock'
+          lock = rustPlatform.buildRustPackage {
+            pname = "lock";
+            version = "1.0.0";
+            src = pkgs.lib.cleanSource ./.; # the folder with the cargo.toml
+            cargoLock.lockFile = ./Cargo.lock;
+            cargoBuildFlags = [ "--bin" "lock" ];
+            doCheck = false; # disable so that these can be built independently
+            # https://nixos.org/manual/nixpkgs/stable/#ssec-installCheck-phase
+            doInstallCheck = false; # disable so that these can be built independently
+          };
         };
 
         devShells.default = pkgs.mkShell {
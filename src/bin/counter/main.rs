@@ -1,24 +1,34 @@
 use app::{config, counter, node, store};
-use std::io;
-use std::path::Path;
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// How the counter is kept. Maelstrom doesn't pass arguments, so it can also be set in the
+    /// environment.
+    #[arg(long, value_enum, env = "COUNTER_MODE", default_value_t = counter::Mode::Kv)]
+    mode: counter::Mode,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-fn main() {
     // Initialize the default subscriber, which logs to stdout
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr) // all debug logs have to go to stderr
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    let p = Path::new("./counter.txt");
-    let mut s = store::FileStore::new(p).expect("failed to create store");
+    // Neither mode keeps anything in the node's store.
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
     let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
         .expect("failed to get config");
-    let mut n: node::Node<store::FileStore, config::SystemTime> = node::Node::new(&mut s, cfg);
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
 
     n.run(
-        io::stdin().lock(), // Each handle is a shared reference to a global buffer of input data to this process. A handle can be lock’d to gain full access to BufRead methods (e.g., .lines()).
-        io::stdout().lock(),
-        counter::listen,
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        counter::Counter::new(args.mode),
     )
-    .expect("failed to start");
+    .await
 }
@@ -0,0 +1,63 @@
+use app::consensus::{Algorithm, Protocol};
+use app::lin_kv::{LinKv, Registers};
+use app::paxos::Paxos;
+use app::raft::Raft;
+use app::{config, node, store};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The consensus protocol the registers are replicated with. Maelstrom doesn't pass
+    /// arguments, so it can also be set in the environment.
+    #[arg(long, value_enum, env = "LIN_KV_CONSENSUS", default_value_t = Algorithm::Raft)]
+    consensus: Algorithm,
+
+    /// Where each node keeps its Raft state so that it survives being killed. It defaults to a
+    /// directory for the run under the system's temporary directory. Paxos only keeps its state
+    /// in memory.
+    #[arg(long, env = "LIN_KV_DIR")]
+    dir: Option<PathBuf>,
+}
+
+// run_dir is where a run's Raft state goes by default. Maelstrom restarts a killed node from the
+// same process it started it from, so its pid tells runs apart but not the restarts in one.
+fn run_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("lin-kv-{}", std::os::unix::process::parent_id()))
+}
+
+// serve runs the node until stdin closes with the registers replicated by `P`.
+async fn serve<P: Protocol<Machine = Registers>>(dir: Option<PathBuf>) -> anyhow::Result<()> {
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+    let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
+        .expect("failed to get config");
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
+
+    let mut lin_kv = LinKv::<P>::new();
+    if let Some(dir) = dir {
+        lin_kv = lin_kv.with_dir(dir);
+    }
+
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        lin_kv,
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Initialize the default subscriber, which logs to stdout
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr) // all debug logs have to go to stderr
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    match args.consensus {
+        Algorithm::Raft => serve::<Raft<Registers>>(Some(args.dir.unwrap_or_else(run_dir))).await,
+        Algorithm::Paxos => serve::<Paxos<Registers>>(args.dir).await,
+    }
+}
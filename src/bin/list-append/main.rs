@@ -0,0 +1,33 @@
+use app::{config, list_append, node, store};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Where the lists are kept. Maelstrom doesn't pass arguments, so it can also be set in the
+    /// environment.
+    #[arg(long, value_enum, env = "LIST_APPEND_MODE", default_value_t = list_append::Mode::Kv)]
+    mode: list_append::Mode,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Initialize the default subscriber, which logs to stdout
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr) // all debug logs have to go to stderr
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+    let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
+        .expect("failed to get config");
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
+
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        list_append::ListAppend::new(args.mode),
+    )
+    .await
+}
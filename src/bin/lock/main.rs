@@ -0,0 +1,56 @@
+use app::consensus::{Algorithm, Protocol};
+use app::lock::{LockService, Locks};
+use app::paxos::Paxos;
+use app::raft::Raft;
+use app::{config, node, store};
+use clap::Parser;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The consensus protocol the locks are replicated with. Maelstrom doesn't pass
+    /// arguments, so it can also be set in the environment.
+    #[arg(long, value_enum, env = "LOCK_CONSENSUS", default_value_t = Algorithm::Raft)]
+    consensus: Algorithm,
+
+    /// Where each node keeps its Raft state so that it survives being killed. Without one it's
+    /// only kept in memory, which is all Paxos supports. Leases are timed by the system clock.
+    #[arg(long, env = "LOCK_DIR")]
+    dir: Option<PathBuf>,
+}
+
+// serve runs the node until stdin closes with the locks replicated by `P`.
+async fn serve<P: Protocol<Machine = Locks>>(dir: Option<PathBuf>) -> anyhow::Result<()> {
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+    let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
+        .expect("failed to get config");
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
+
+    let mut lock = LockService::<P>::new();
+    if let Some(dir) = dir {
+        lock = lock.with_dir(dir);
+    }
+
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        lock,
+    )
+    .await
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Initialize the default subscriber, which logs to stdout
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr) // all debug logs have to go to stderr
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    match args.consensus {
+        Algorithm::Raft => serve::<Raft<Locks>>(args.dir).await,
+        Algorithm::Paxos => serve::<Paxos<Locks>>(args.dir).await,
+    }
+}
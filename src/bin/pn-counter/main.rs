@@ -0,0 +1,23 @@
+use app::{config, node, pn_counter, store};
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    // Initialize the default subscriber, which logs to stdout
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr) // all debug logs have to go to stderr
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    // The counter is only ever kept in memory and gossiped between nodes.
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+    let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
+        .expect("failed to get config");
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
+
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        pn_counter::Counter::default(),
+    )
+    .await
+}
@@ -1,24 +1,81 @@
 use app::{config, node, replicated_log, store};
-use std::io;
-use std::path::Path;
+use clap::Parser;
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// How the logs are kept. Maelstrom doesn't pass arguments, so it can also be set in the
+    /// environment.
+    #[arg(long, value_enum, env = "REPLICATED_LOG_MODE", default_value_t = replicated_log::Mode::Kv)]
+    mode: replicated_log::Mode,
+
+    /// Where the logs are kept in `local` and `leader` mode. Every node needs its own, so it
+    /// defaults to a new temporary directory.
+    #[arg(long, env = "REPLICATED_LOG_DIR")]
+    dir: Option<PathBuf>,
+
+    /// Where segments that are compacted away are moved to rather than deleted.
+    #[arg(long, env = "REPLICATED_LOG_ARCHIVE_DIR")]
+    archive_dir: Option<PathBuf>,
+
+    /// How many of the most recent messages of each log to keep, even if they were never
+    /// committed.
+    #[arg(long, env = "REPLICATED_LOG_RETAIN_MESSAGES")]
+    retain_messages: Option<u64>,
+
+    /// How many seconds to keep messages for, even if they were never committed.
+    #[arg(long, env = "REPLICATED_LOG_RETAIN_SECS")]
+    retain_secs: Option<u64>,
+
+    /// The most messages of any one log a `poll_ok` carries.
+    #[arg(long, env = "REPLICATED_LOG_POLL_MAX_MESSAGES", default_value_t = replicated_log::PollLimits::default().max_messages)]
+    poll_max_messages: usize,
+
+    /// Roughly the most bytes of messages a `poll_ok` carries.
+    #[arg(long, env = "REPLICATED_LOG_POLL_MAX_BYTES", default_value_t = replicated_log::PollLimits::default().max_bytes)]
+    poll_max_bytes: usize,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-fn main() {
     // Initialize the default subscriber, which logs to stdout
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr) // all debug logs have to go to stderr
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
-    let p = Path::new("./log.txt");
-    let mut s = store::FileStore::new(p).expect("failed to create store");
+    // The temporary directory is removed when it's dropped, so it has to outlive the node.
+    let tmp = tempfile::tempdir().expect("failed to create tempdir");
+    let dir = args.dir.unwrap_or_else(|| tmp.path().to_path_buf());
+    info!("keeping logs in {:?}", dir);
+    let options = store::SegmentedLogOptions {
+        archive_dir: args.archive_dir,
+        ..Default::default()
+    };
+    let log = store::SegmentedLog::open(dir, options).expect("failed to open log");
+    let retention = store::Retention {
+        max_records: args.retain_messages,
+        max_age: args.retain_secs.map(std::time::Duration::from_secs),
+    };
+    let poll_limits = replicated_log::PollLimits {
+        max_messages: args.poll_max_messages,
+        max_bytes: args.poll_max_bytes,
+    };
+
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
     let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
         .expect("failed to get config");
-    let mut n: node::Node<store::FileStore> = node::Node::new(&mut s, cfg);
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
 
     n.run(
-        io::stdin().lock(), // Each handle is a shared reference to a global buffer of input data to this process. A handle can be lock’d to gain full access to BufRead methods (e.g., .lines()).
-        io::stdout().lock(),
-        replicated_log::listen,
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        replicated_log::ReplicatedLog::new(args.mode, log)
+            .with_retention(retention)
+            .with_poll_limits(poll_limits),
     )
-    .expect("failed to start");
+    .await
 }
@@ -0,0 +1,34 @@
+use app::{config, node, store, txn};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// What other transactions may see of one that's running. Maelstrom doesn't pass arguments,
+    /// so it can also be set in the environment.
+    #[arg(long, value_enum, env = "TXN_ISOLATION", default_value_t = txn::Isolation::ReadCommitted)]
+    isolation: txn::Isolation,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    // Initialize the default subscriber, which logs to stdout
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr) // all debug logs have to go to stderr
+        .with_max_level(tracing::Level::DEBUG)
+        .init();
+
+    // The registers are only ever kept in memory and replicated between nodes.
+    let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+    let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
+        .expect("failed to get config");
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
+
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        txn::Txn::new(args.isolation),
+    )
+    .await
+}
@@ -1,7 +1,7 @@
 use app::{config, node, store, unique};
-use std::io;
 
-fn main() {
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
     // Initialize the default subscriber, which logs to stdout
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr) // all debug logs have to go to stderr
@@ -9,11 +9,15 @@ fn main() {
         .init();
 
     let buf: Vec<u8> = Vec::new();
-    let mut s = store::MemoryStore::new(buf).expect("failed to create store");
+    let s = store::MemoryStore::new(buf).expect("failed to create store");
     let cfg = config::Config::<config::SystemTime>::new(config::SystemTime {})
         .expect("failed to get config");
-    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(&mut s, cfg);
+    let mut n: node::Node<store::MemoryStore, config::SystemTime> = node::Node::new(s, cfg);
 
-    n.run(io::stdin().lock(), io::stdout().lock(), unique::listen)
-        .expect("failed to start");
+    n.run(
+        tokio::io::BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        unique::Unique,
+    )
+    .await
 }
@@ -1,13 +1,14 @@
-use crate::payload;
-use crate::payload::{Payload, ResponseBody};
+use crate::payload::{Message, Payload, ResponseBody};
 use crate::{config, node, store};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use serde_with::{TimestampSeconds, serde_as};
 use std::collections::{HashMap, HashSet};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::SeekFrom;
 use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc;
-use tracing::{error, info};
+use tracing::{debug, error, info};
 
 // In this challenge, you’ll need to implement a broadcast system that gossips
 // messages between all nodes in the cluster. Gossiping is a common way to propagate
@@ -25,33 +26,44 @@ use tracing::{error, info};
 // 50-70% initial coverage for reliable full propagation
 // Fanout of 3-5 nodes per gossip round
 // Log(N) rounds to reach full coverage (where N = total nodes)
+//
+// Who a node gossips to, its neighborhood, is picked at startup so that strategies can be
+// compared:
+// - `topology` uses the neighbors Maelstrom sends in the `topology` message.
+// - `random` gives every other node a random priority and gossips to those whose priority is
+//   over 33. The priorities are drawn from a seed, which is logged so that a run can be repeated.
+// - `tree` arranges the nodes, sorted by id, in a tree where each node has up to `TREE_FANOUT`
+//   children, and gossips to its parent and children. Every node works out the same tree, so a
+//   message reaches everyone in at most twice the tree's depth in hops.
+
+// TREE_FANOUT is how many children each node has in the `tree` neighborhood.
+const TREE_FANOUT: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Neighborhood {
+    #[default]
+    Topology,
+    Random,
+    Tree,
+}
 
 #[serde_as]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
 pub enum RequestBody {
-    Init {
-        msg_id: u32,
-        node_id: String,
-        node_ids: Vec<String>,
-    },
     Topology {
-        msg_id: u32,
         topology: HashMap<String, Vec<String>>,
     },
     Broadcast(BroadcastMessage),
-    Read {
-        msg_id: u32,
-    },
-    #[serde(other)]
-    Other,
+    Read,
 }
 
 #[serde_as]
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct BroadcastMessage {
-    msg_id: u32,
+    // Maelstrom clients don't include a src in the body, only nodes gossiping do.
+    #[serde(default)]
     src: String,
     message: u32,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -77,25 +89,10 @@ pub struct ReadRespData {
 
 // Goal: have a generic type Payload for input and output
 #[derive(Deserialize, Serialize, Debug)]
+#[serde(untagged)]
 pub enum Body {
     Response(ResponseBody<()>), // use for T: None
     ReadRespData(ResponseBody<ReadRespData>),
-    BroadcastMessage(BroadcastMessage),
-}
-
-impl Into<payload::RequestBody<BroadcastMessage>> for BroadcastMessage {
-    fn into(self) -> payload::RequestBody<BroadcastMessage> {
-        payload::RequestBody {
-            msg_id: self.msg_id,
-            data: BroadcastMessage {
-                msg_id: self.msg_id,
-                src: self.src,
-                message: self.message,
-                expiration: self.expiration,
-                state: self.state,
-            },
-        }
-    }
 }
 
 // #[expect(dead_code)]
@@ -144,25 +141,29 @@ impl Into<payload::RequestBody<BroadcastMessage>> for BroadcastMessage {
 //     }
 // }
 
-#[allow(dead_code)]
 fn anthropomorphic_gossip<S, T>(
     node: &mut node::Node<S, T>,
-    tx: mpsc::UnboundedSender<Payload<Body>>,
+    msg_id: u32,
     msg: BroadcastMessage,
-) where
+) -> Vec<Payload<Body>>
+where
     S: store::Store + std::fmt::Debug,
     T: config::TimeSource,
 {
     // 1/ messages need to have a relevancy TTL or expiration
     let expiration = msg
         .expiration
-        .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(3));
+        .unwrap_or_else(|| node.config.time_source.now() + Duration::from_secs(3));
 
     let mut message_state = msg.state.unwrap_or_else(|| MessageState {
         seen_by: HashSet::<String>::new(),
     });
 
-    if node.config.time_source.now() < expiration {
+    // Only the first time we hear about a message do we pass it on. Hearing it again is most
+    // likely a neighbor retrying because our ack got lost, and the outbox retries our own sends.
+    let first_seen = node.seen.insert(msg.message);
+
+    if first_seen && node.config.time_source.now() < expiration {
         // 2/ nodes store where they heard about a message and this occurs before sending to neighbors
         // so that a node doesn't send a message back to the neighbor that sent the message.
         message_state.seen_by.insert(msg.src.clone());
@@ -175,31 +176,33 @@ fn anthropomorphic_gossip<S, T>(
                 continue;
             }
 
-            if let Err(e) = tx.send(Payload {
-                src: node.id.clone(),
-                dest: k.to_owned(),
-                body: Body::BroadcastMessage(BroadcastMessage {
+            // The neighbor acks with a broadcast_ok. Until then, the outbox keeps re-sending
+            // it until the message expires.
+            node.outbox.send(
+                Payload {
                     src: node.id.clone(),
-                    msg_id: msg.msg_id,
-                    message: msg.message,
-                    expiration: Some(expiration.clone()),
-                    state: Some(message_state.clone()),
-                }),
-            }) {
-                error!("failed to broadcast message: {}", e);
-            };
+                    dest: k.to_owned(),
+                    body: RequestBody::Broadcast(BroadcastMessage {
+                        src: node.id.clone(),
+                        message: msg.message,
+                        expiration: Some(expiration),
+                        state: Some(message_state.clone()),
+                    }),
+                },
+                expiration,
+            );
         }
     }
 
     // 4/ TODO: strangers come from a node's "world" at random
 
     // 5/ Persist unique values to the store.
-    if node.seen.insert(msg.msg_id) {
+    if first_seen {
         let mut s = node
             .store
             .lock()
             .expect("failed to take store lock for writing");
-        if let Err(e) = serde_json::ser::to_writer(&mut *s, &msg.msg_id) {
+        if let Err(e) = serde_json::ser::to_writer(&mut *s, &msg.message) {
             error!("failed to serialized message to be stored: {}", e);
         };
         if let Err(e) = writeln!(&mut *s) {
@@ -207,112 +210,179 @@ fn anthropomorphic_gossip<S, T>(
         }
     }
 
-    if let Err(e) = tx.send(Payload {
+    vec![Payload {
         src: node.id.clone(),
-        dest: msg.src.clone(),
+        dest: msg.src,
         body: Body::Response(ResponseBody {
             typ: "broadcast_ok".to_string(),
-            in_reply_to: msg.msg_id,
+            in_reply_to: msg_id,
             data: None,
         }),
-    }) {
-        error!("failed to broadcast message: {}", e);
+    }]
+}
+
+// tree returns `id`'s parent and children in a tree of `ids` with `fanout` children per node.
+fn tree(ids: &[String], id: &str, fanout: usize) -> Vec<String> {
+    let mut ids = ids.to_vec();
+    ids.sort();
+    let Some(i) = ids.iter().position(|n| n == id) else {
+        return vec![];
+    };
+    let parent = i.checked_sub(1).map(|p| p / fanout);
+    let children = fanout * i + 1..(fanout * (i + 1) + 1).min(ids.len());
+    parent
+        .into_iter()
+        .chain(children)
+        .map(|n| ids[n].clone())
+        .collect()
+}
+
+pub struct Broadcast {
+    neighborhood: Neighborhood,
+    // What the `random` neighborhood is drawn from. Without one, a seed is picked at random.
+    seed: Option<u64>,
+}
+
+impl Broadcast {
+    pub fn new(neighborhood: Neighborhood) -> Self {
+        Self {
+            neighborhood,
+            seed: None,
+        }
+    }
+
+    /// with_seed draws the `random` neighborhood from `seed`, so that it's the same every run.
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // set_neighborhood makes `neighbors` the node's neighborhood, leaving out any it doesn't know.
+    fn set_neighborhood<S, T>(node: &mut node::Node<S, T>, neighbors: &[String])
+    where
+        T: config::TimeSource,
+        S: store::Store,
+    {
+        node.neighborhood = neighbors
+            .iter()
+            .filter_map(|n| {
+                let priority = node.world.get(n)?.priority;
+                Some((n.clone(), node::Metadata { priority }))
+            })
+            .collect();
+        debug!("gossiping to {:?}", node.neighborhood.keys());
+    }
+
+    // set_random_neighborhood gives every peer a priority drawn from `seed` and the node's id,
+    // so that nodes sharing a seed still pick different neighbors, and keeps those over 33.
+    fn set_random_neighborhood<S, T>(node: &mut node::Node<S, T>, seed: u64)
+    where
+        T: config::TimeSource,
+        S: store::Store,
+    {
+        let mut h = DefaultHasher::new();
+        (seed, &node.id).hash(&mut h);
+        let mut rng = StdRng::seed_from_u64(h.finish());
+
+        let mut peers: Vec<String> = node.world.keys().cloned().collect();
+        peers.sort();
+        node.neighborhood.clear();
+        for n in peers {
+            let priority: u8 = rng.random_range(0..=100);
+            node.world.insert(n.clone(), node::Metadata { priority });
+            if priority > 33 {
+                node.neighborhood.insert(n, node::Metadata { priority });
+            }
+        }
+        debug!("gossiping to {:?}", node.neighborhood.keys());
     }
 }
 
-pub async fn listen<'a, S, T>(
-    node: &'a mut node::Node<S, T>,
-    mut rx: mpsc::UnboundedReceiver<Payload<RequestBody>>,
-    tx: mpsc::UnboundedSender<Payload<Body>>,
-) where
+impl<S, T> node::Handler<S, T> for Broadcast
+where
     T: config::TimeSource,
     S: store::Store + std::fmt::Debug,
 {
-    loop {
-        let msg = match rx.recv().await {
-            Some(msg) => msg,
-            None => continue,
-        };
+    type Request = RequestBody;
+    type Response = Body;
 
-        match msg.body {
-            RequestBody::Init {
-                msg_id,
-                node_id,
-                node_ids,
-            } => {
-                node.init(node_id, node_ids);
-
-                if let Err(e) = tx.clone().send(Payload {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body::Response(ResponseBody {
-                        typ: "init_ok".to_string(),
-                        in_reply_to: msg_id,
-                        data: None,
-                    }),
-                }) {
-                    error!("errored while responding: {}", e);
-                };
+    fn init(&mut self, node: &mut node::Node<S, T>) -> anyhow::Result<()> {
+        match self.neighborhood {
+            // Nobody's a neighbor until the topology arrives.
+            Neighborhood::Topology => node.neighborhood.clear(),
+            Neighborhood::Random => {
+                let seed = self.seed.unwrap_or_else(rand::random);
+                info!("drawing the neighborhood from seed {}", seed);
+                Self::set_random_neighborhood(node, seed);
             }
+            Neighborhood::Tree => {
+                let mut ids: Vec<String> = node.world.keys().cloned().collect();
+                ids.push(node.id.clone());
+                let neighbors = tree(&ids, &node.id, TREE_FANOUT);
+                Self::set_neighborhood(node, &neighbors);
+            }
+        }
+        Ok(())
+    }
 
-            RequestBody::Topology {
-                msg_id,
-                topology: _, // NOTE: we don't use the topology message because I'm trying to define my own random neighborhood generator in node.init().
-            } => {
-                if let Err(e) = tx.clone().send(Payload {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body::Response(ResponseBody {
-                        typ: "topology_ok".to_string(),
-                        in_reply_to: msg_id,
-                        data: None,
-                    }),
-                }) {
-                    error!("error sending topology_ok: {}", e);
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> anyhow::Result<Vec<Payload<Body>>> {
+        match &msg.body.data {
+            RequestBody::Topology { topology } => {
+                if self.neighborhood == Neighborhood::Topology {
+                    let neighbors = topology.get(&node.id).cloned().unwrap_or_default();
+                    Self::set_neighborhood(node, &neighbors);
                 }
+                Ok(vec![msg.reply("topology_ok", None).map(Body::Response)])
             }
 
             RequestBody::Broadcast(BroadcastMessage {
                 src: _src,
-                msg_id,
                 message,
                 expiration,
                 state,
             }) => {
-                anthropomorphic_gossip(
-                    node,
-                    tx.clone(),
-                    BroadcastMessage {
-                        src: msg.src.clone(),
-                        msg_id,
-                        message,
-                        // expiration is set by the first gossip node
-                        expiration: Some(expiration.unwrap_or_else(|| {
-                            let random_seconds = rand::rng().random_range(1..=5); // 1 to 5 inclusive
-                            node.config.time_source.now()
-                                + std::time::Duration::from_secs(random_seconds)
-                        })),
-                        // if state is empty it's likely due to this being the first gossip node receiving
-                        // the message from a maelstrom server node.
-                        state: Some(state.unwrap_or_else(|| {
-                            let mut seen_by: HashSet<String> = HashSet::new();
-                            seen_by.insert(msg.src);
-
-                            MessageState { seen_by }
-                        })),
-                    },
-                )
+                let gossip = BroadcastMessage {
+                    src: msg.src.clone(),
+                    message: *message,
+                    // expiration is set by the first gossip node
+                    expiration: Some(expiration.unwrap_or_else(|| {
+                        let random_seconds = rand::rng().random_range(1..=5); // 1 to 5 inclusive
+                        node.config.time_source.now()
+                            + std::time::Duration::from_secs(random_seconds)
+                    })),
+                    // if state is empty it's likely due to this being the first gossip node receiving
+                    // the message from a maelstrom server node.
+                    state: Some(state.clone().unwrap_or_else(|| {
+                        let mut seen_by: HashSet<String> = HashSet::new();
+                        seen_by.insert(msg.src.clone());
+
+                        MessageState { seen_by }
+                    })),
+                };
+                Ok(anthropomorphic_gossip(node, msg.body.msg_id, gossip))
             }
 
-            RequestBody::Read { msg_id } => {
+            RequestBody::Read => {
+                debug!("{} gossip message(s) not yet acked", node.outbox.len());
+
                 let mut buf = String::new();
-                if let Err(e) = node
-                    .store
-                    .lock()
-                    .expect("expected to acquire store lock for reading")
-                    .read_to_string(&mut buf)
                 {
-                    error!("failed to read store: {e}");
+                    let mut s = node
+                        .store
+                        .lock()
+                        .expect("expected to acquire store lock for reading");
+                    // Every read returns everything we've seen, not only what's new since the
+                    // last read.
+                    if let Err(e) = s
+                        .seek(SeekFrom::Start(0))
+                        .and_then(|_| s.read_to_string(&mut buf))
+                    {
+                        error!("failed to read store: {e}");
+                    }
                 }
 
                 let mut seen = Vec::<u32>::new();
@@ -321,248 +391,77 @@ pub async fn listen<'a, S, T>(
                     seen.push(v);
                 }
 
-                if let Err(e) = tx.clone().send(Payload {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: Body::ReadRespData(ResponseBody {
-                        typ: "read_ok".to_string(),
-                        in_reply_to: msg_id,
-                        data: Some(ReadRespData { messages: seen }),
-                    }),
-                }) {
-                    error!("failed to put message on the queue: {}", e);
-                };
-            }
-
-            RequestBody::Other => {
-                info!("other: {:?}", msg);
+                Ok(vec![
+                    msg.reply("read_ok", Some(ReadRespData { messages: seen }))
+                        .map(Body::ReadRespData),
+                ])
             }
         }
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use std::time::{self, Duration};
-//
-//     use crate::config::{MockTime, TimeSource};
-//
-//     use super::*;
-//     use std::{collections::HashSet, io::Cursor};
-//
-//     struct BroadcastCase {
-//         name: String,
-//         setup: fn(&mut store::MemoryStore, SystemTime) -> Vec<u8>,
-//         expected: fn(SystemTime) -> HashSet<String>,
-//     }
-//
-//     #[test]
-//     fn broadcast() {
-//         let test_cases = vec![
-//             BroadcastCase {
-//                 name: String::from("one"),
-//                 setup: |s: &mut store::MemoryStore, expiration: SystemTime| -> Vec<u8> {
-//                     let cfg = config::Config::<config::MockTime>::new(config::MockTime {
-//                         now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
-//                     })
-//                     .expect("failed to get config");
-//
-//                     let mut n = node::Node::<store::MemoryStore, config::MockTime>::new(s, cfg);
-//                     n.init(String::from("n1"), vec![]);
-//                     n.neighborhood
-//                         .insert(String::from("n2"), node::Metadata { priority: 99 });
-//                     n.neighborhood
-//                         .insert(String::from("n3"), node::Metadata { priority: 99 });
-//
-//                     let messages: Vec<String> = vec![
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("c1"),
-//                             dest: String::from("n1"),
-//                             body: RequestBody::Topology {
-//                                 msg_id: 1,
-//                                 topology: HashMap::from([(
-//                                     String::from("n1"),
-//                                     vec![String::from("ignored")],
-//                                 )]),
-//                             },
-//                         })
-//                         .expect("serializing topology message should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("c1"),
-//                             dest: String::from("n1"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 2,
-//                                 message: 222,
-//                                 expiration: Some(expiration),
-//                                 state: None,
-//                             },
-//                         })
-//                         .expect("serializing broadcast message should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("c1"),
-//                             dest: String::from("n1"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 3,
-//                                 message: 333,
-//                                 expiration: Some(expiration),
-//                                 state: None,
-//                             },
-//                         })
-//                         .expect("serializing broadcast message should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("c1"),
-//                             dest: String::from("n1"),
-//                             body: RequestBody::Read { msg_id: 5 },
-//                         })
-//                         .expect("serializing read message should work"),
-//                     ];
-//
-//                     let mut sent = Cursor::new(Vec::<u8>::new());
-//
-//                     for m in messages {
-//                         let read_cursor = Cursor::new(m);
-//                         listen(&mut n, read_cursor, &mut sent)
-//                             .expect("failed to listen to message");
-//                     }
-//
-//                     sent.into_inner()
-//                 },
-//                 // We need to keep a list of messages that a node "sends".
-//                 // To assert that it sends a re-broadcast message. Instead of checking node states.
-//                 // "Your node should propagate values it sees from broadcast messages to the
-//                 // other nodes in the cluster."
-//                 // https://fly.io/dist-sys/3b/
-//                 expected: |expiration: SystemTime| -> HashSet<String> {
-//                     HashSet::from([
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("c1"),
-//                             body: ResponseBody::<()> {
-//                                 typ: String::from("topology_ok"),
-//                                 in_reply_to: 1,
-//                                 data: None,
-//                             },
-//                         })
-//                         .expect("serializing expected message 1 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("c1"),
-//                             body: ResponseBody::<()> {
-//                                 typ: String::from("broadcast_ok"),
-//                                 in_reply_to: 2,
-//                                 data: None,
-//                             },
-//                         })
-//                         .expect("serializing expected message 2 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("n2"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 2,
-//                                 message: 222,
-//                                 expiration: Some(expiration),
-//                                 state: Some(MessageState {
-//                                     seen_by: HashSet::from([String::from("c1")]),
-//                                 }),
-//                             },
-//                         })
-//                         .expect("serializing expected message 3 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("n3"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 2,
-//                                 message: 222,
-//                                 expiration: Some(expiration),
-//                                 state: Some(MessageState {
-//                                     seen_by: HashSet::from([String::from("c1")]),
-//                                 }),
-//                             },
-//                         })
-//                         .expect("serializing expected message 4 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("c1"),
-//                             body: ResponseBody::<()> {
-//                                 typ: String::from("broadcast_ok"),
-//                                 in_reply_to: 3,
-//                                 data: None,
-//                             },
-//                         })
-//                         .expect("serializing expected message 5 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("n2"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 3,
-//                                 message: 333,
-//                                 expiration: Some(expiration),
-//                                 state: Some(MessageState {
-//                                     seen_by: HashSet::from([String::from("c1")]),
-//                                 }),
-//                             },
-//                         })
-//                         .expect("serializing expected message 6 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("n3"),
-//                             body: RequestBody::Broadcast {
-//                                 msg_id: 3,
-//                                 message: 333,
-//                                 expiration: Some(expiration),
-//                                 state: Some(MessageState {
-//                                     seen_by: HashSet::from([String::from("c1")]),
-//                                 }),
-//                             },
-//                         })
-//                         .expect("serializing expected message 7 as json should work"),
-//                         serde_json::to_string(&Payload {
-//                             src: String::from("n1"),
-//                             dest: String::from("c1"),
-//                             body: ResponseBody::<ReadRespData> {
-//                                 typ: String::from("read_ok"),
-//                                 in_reply_to: 5,
-//                                 data: Some(ReadRespData {
-//                                     messages: vec![2, 3],
-//                                 }),
-//                             },
-//                         })
-//                         .expect("serializing expected message 8 as json should work"),
-//                     ])
-//                 },
-//             },
-//             // TODO - include more sophisticated test case where priorities aren't "guaranteed" to
-//             // send to all nodes in a neighborhood.
-//             //
-//             // TODO - include test case where messages have expired
-//         ];
-//
-//         for case in test_cases {
-//             info!("TEST: {:?}", case.name);
-//
-//             let mut s =
-//                 store::MemoryStore::new(Vec::<u8>::new()).expect("failed to create memory store");
-//
-//             let expiration = MockTime {
-//                 now: SystemTime::UNIX_EPOCH
-//                     + Duration::from_secs(1757680326)
-//                     + Duration::from_secs(300),
-//             }
-//             .now();
-//
-//             let response = (case.setup)(&mut s, expiration);
-//             let response_str = String::from_utf8(response).expect("Invalid UTF-8");
-//             let actual: HashSet<String> =
-//                 HashSet::from_iter(response_str.lines().map(|s| s.to_string()));
-//             let expected = (case.expected)(expiration);
-//
-//             assert_eq!(
-//                 expected,
-//                 actual,
-//                 "Sets don't match: \n\tactual has (expected doesn't) {:#?}, \n\texpected has (actual doesn't) {:#?}\n",
-//                 actual.difference(&expected).collect::<Vec<_>>(),
-//                 expected.difference(&actual).collect::<Vec<_>>()
-//             );
-//         }
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time};
+
+    #[test]
+    fn tree_neighborhood() {
+        let ids: Vec<String> = (1..=7).map(|i| format!("n{}", i)).collect();
+        assert_eq!(tree(&ids, "n1", 2), vec!["n2", "n3"]);
+        assert_eq!(tree(&ids, "n2", 2), vec!["n1", "n4", "n5"]);
+        assert_eq!(tree(&ids, "n3", 2), vec!["n1", "n6", "n7"]);
+        assert_eq!(tree(&ids, "n7", 2), vec!["n3"]);
+        assert!(tree(&ids, "n8", 2).is_empty());
+    }
+
+    #[test]
+    fn random_neighborhood() {
+        let neighbors = |id: &str, seed: u64| {
+            let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+            let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+                now: time::UNIX_EPOCH,
+            })
+            .expect("failed to get config");
+            let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+            n.init(
+                id.to_string(),
+                (1..=20).map(|i| format!("n{}", i)).collect(),
+            );
+            Broadcast::set_random_neighborhood(&mut n, seed);
+            let mut neighbors: Vec<String> = n.neighborhood.keys().cloned().collect();
+            neighbors.sort();
+            neighbors
+        };
+
+        // The same seed gives the same neighborhood, and only ever other nodes.
+        assert_eq!(neighbors("n1", 7), neighbors("n1", 7));
+        assert!(!neighbors("n1", 7).is_empty());
+        assert!(!neighbors("n1", 7).contains(&"n1".to_string()));
+        assert_ne!(neighbors("n1", 7), neighbors("n1", 8));
+    }
+
+    #[tokio::test]
+    async fn topology_neighborhood() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}
+{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n3"],"n2":["n1","n3"],"n3":["n1","n2"]}}}
+"#;
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(
+            Cursor::new(input.as_bytes()),
+            &mut actual,
+            Broadcast::new(Neighborhood::Topology),
+        )
+        .await
+        .expect("run failed");
+        let neighbors: Vec<&String> = n.neighborhood.keys().collect();
+        assert_eq!(neighbors, vec!["n3"]);
+    }
+}
@@ -1,4 +1,6 @@
-pub trait TimeSource {
+use std::sync::Arc;
+
+pub trait TimeSource: Send + Sync + 'static {
     fn now(&self) -> std::time::SystemTime;
 }
 
@@ -22,12 +24,15 @@ impl TimeSource for MockTime {
 }
 
 pub struct Config<T: TimeSource> {
-    // This is where we set the TYPE of timesource
-    pub time_source: T,
+    // This is where we set the TYPE of timesource. It's shared with whatever else in the node
+    // needs the time, e.g., the outbox.
+    pub time_source: Arc<T>,
 }
 
 impl<T: TimeSource> Config<T> {
     pub fn new(time_source: T) -> Result<Self, anyhow::Error> {
-        Ok(Config { time_source })
+        Ok(Config {
+            time_source: Arc::new(time_source),
+        })
     }
 }
@@ -0,0 +1,338 @@
+use crate::node;
+use crate::payload::{ErrorBody, ErrorCode, Message, Payload, ResponseBody};
+use crate::rpc::Rpc;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, error};
+
+// What the consensus modules (`raft.rs`, `paxos.rs`) have in common: they replicate commands for
+// a `StateMachine` so that every node applies the same ones in the same order, and only one node
+// at a time, the leader, takes new commands.
+//
+// Each is written as a `Protocol` that has no I/O of its own: it's handed messages and the time,
+// and returns the messages to send. `Replica` runs any of them on top of the node's RPC layer, so
+// a workload can pick one at startup and treat them all the same.
+
+// TICK is how often a protocol's timers are checked.
+const TICK: Duration = Duration::from_millis(10);
+
+// RPC_TIMEOUT is how long to wait for a reply from another replica. A lost one is as good as a
+// refusal, protocols retry on their own timers.
+const RPC_TIMEOUT: Duration = Duration::from_millis(500);
+
+// CLIENT_TIMEOUT is how long a client waits for its command to be committed, or for the leader
+// to answer a forwarded request, before being told it may or may not have happened.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Algorithm is a consensus protocol a workload can be started with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Algorithm {
+    #[default]
+    Raft,
+    Paxos,
+}
+
+/// RequestBody is what a workload replicated by a [`Protocol`] is sent: either a client's request
+/// `C` or one of the protocol's own requests `Q`, both told apart by `type`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum RequestBody<C, Q> {
+    Client(C),
+    Consensus(Q),
+}
+
+// Deriving this as an untagged enum would turn a `type` neither side knows into a generic
+// mismatch, so that the client would be told its request was malformed rather than unsupported.
+impl<'de, C: DeserializeOwned, Q: DeserializeOwned> Deserialize<'de> for RequestBody<C, Q> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let body = Value::deserialize(deserializer)?;
+        let client = match C::deserialize(&body) {
+            Ok(request) => return Ok(RequestBody::Client(request)),
+            Err(e) => e,
+        };
+        let consensus = match Q::deserialize(&body) {
+            Ok(request) => return Ok(RequestBody::Consensus(request)),
+            Err(e) => e,
+        };
+        // Whichever side knows the type has the error that's worth passing on.
+        match (
+            node::is_unknown_type(&client),
+            node::is_unknown_type(&consensus),
+        ) {
+            (true, true) => Err(de::Error::custom(format!(
+                "unknown variant `{}`, expected a client or consensus request",
+                body.get("type").and_then(Value::as_str).unwrap_or_default()
+            ))),
+            (true, false) => Err(de::Error::custom(consensus)),
+            (false, _) => Err(de::Error::custom(client)),
+        }
+    }
+}
+
+/// StateMachine is what commands are replicated for. Every node applies the same committed
+/// commands in the same order, so `apply` has to be deterministic.
+pub trait StateMachine: Send + 'static {
+    type Command: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+    type Output: Send + 'static;
+    /// Snapshot is everything the state machine needs to pick up where it was.
+    type Snapshot: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+    fn snapshot(&self) -> Self::Snapshot;
+    /// restore replaces the state machine's state with what's in `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// NotLeader is why a command can't be proposed. `leader` is who this node last heard was the
+/// leader, if anyone.
+#[derive(Debug, Clone)]
+pub struct NotLeader {
+    pub leader: Option<String>,
+}
+
+/// Applied resolves with what a proposed command returned once it's applied, or fails if it
+/// wasn't, or may not have been, committed.
+pub type Applied<O> = oneshot::Receiver<Result<O, ErrorBody>>;
+
+pub(crate) type Resolver<O> = oneshot::Sender<Result<O, ErrorBody>>;
+
+/// Outgoing is the requests a protocol wants sent, by destination.
+pub type Outgoing<R> = Vec<(String, R)>;
+
+/// Protocol is one node's part of a consensus algorithm.
+pub trait Protocol: Send + Sized + 'static {
+    type Machine: StateMachine;
+    /// Request is the `type`-tagged body of a message one node sends another.
+    type Request: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+    type Reply: Debug + Clone + Serialize + DeserializeOwned + Send + 'static;
+
+    /// open starts node `id` of a cluster that also has `peers`. If there's a `dir`, whatever has
+    /// to survive a crash is kept there, and read back from it first.
+    fn open(
+        id: String,
+        peers: Vec<String>,
+        machine: Self::Machine,
+        dir: Option<&Path>,
+        now: Instant,
+    ) -> io::Result<Self>;
+
+    /// tick does whatever's due by `now`, e.g., a heartbeat or an election.
+    fn tick(&mut self, now: Instant) -> Outgoing<Self::Request>;
+
+    /// propose takes a command if this node is the leader. What it returns once applied is sent
+    /// on the returned channel.
+    fn propose(
+        &mut self,
+        command: <Self::Machine as StateMachine>::Command,
+    ) -> Result<Applied<<Self::Machine as StateMachine>::Output>, NotLeader>;
+
+    /// handle answers a request from another node.
+    fn handle(&mut self, req: &Self::Request, now: Instant) -> Self::Reply;
+
+    /// handle_reply takes in a peer's reply to one of our requests.
+    fn handle_reply(
+        &mut self,
+        from: &str,
+        reply: Self::Reply,
+        now: Instant,
+    ) -> Outgoing<Self::Request>;
+
+    /// reply_type is the `type` of the reply to `req`.
+    fn reply_type(req: &Self::Request) -> &'static str;
+}
+
+/// Replica runs a [`Protocol`] on the node's RPC layer, with a task that keeps its timers.
+pub struct Replica<P: Protocol> {
+    id: String,
+    protocol: Arc<Mutex<P>>,
+    rpc: Rpc,
+}
+
+impl<P: Protocol> Clone for Replica<P> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            protocol: self.protocol.clone(),
+            rpc: self.rpc.clone(),
+        }
+    }
+}
+
+impl<P: Protocol> Replica<P> {
+    /// start opens the protocol, see [`Protocol::open`], and starts its timers.
+    pub fn start(
+        id: String,
+        mut peers: Vec<String>,
+        machine: P::Machine,
+        dir: Option<&Path>,
+        rpc: Rpc,
+    ) -> io::Result<Self> {
+        peers.sort();
+        let protocol = P::open(id.clone(), peers, machine, dir, Instant::now())?;
+        let replica = Self {
+            id,
+            protocol: Arc::new(Mutex::new(protocol)),
+            rpc,
+        };
+
+        let ticker = replica.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(TICK);
+            loop {
+                tick.tick().await;
+                let out = ticker.lock().tick(Instant::now());
+                ticker.send(out);
+            }
+        });
+        Ok(replica)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, P> {
+        self.protocol.lock().expect("failed to take protocol lock")
+    }
+
+    /// propose replicates `command`, see [`Protocol::propose`].
+    pub fn propose(
+        &self,
+        command: <P::Machine as StateMachine>::Command,
+    ) -> Result<Applied<<P::Machine as StateMachine>::Output>, NotLeader> {
+        self.lock().propose(command)
+    }
+
+    /// handle answers a request from another node with the type and body of the reply.
+    pub fn handle(&self, req: &P::Request) -> (&'static str, P::Reply) {
+        (P::reply_type(req), self.lock().handle(req, Instant::now()))
+    }
+
+    // send sends each request and hands its reply, if one comes, back to the protocol.
+    fn send(&self, out: Outgoing<P::Request>) {
+        for (dest, req) in out {
+            let call = self.rpc.call::<_, Value>(
+                Payload {
+                    src: self.id.clone(),
+                    dest: dest.clone(),
+                    body: req,
+                },
+                RPC_TIMEOUT,
+            );
+            let replica = self.clone();
+            tokio::spawn(async move {
+                let reply = match call.await {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        debug!("no reply from {}: {}", dest, e);
+                        return;
+                    }
+                };
+                match serde_json::from_value::<P::Reply>(reply.body) {
+                    Ok(reply) => {
+                        let out = replica.lock().handle_reply(&dest, reply, Instant::now());
+                        replica.send(out);
+                    }
+                    Err(e) => debug!("bad reply from {}: {}", dest, e),
+                }
+            });
+        }
+    }
+}
+
+impl<P, V> Replica<P>
+where
+    P: Protocol,
+    P::Machine: StateMachine<Output = Result<Option<V>, ErrorBody>>,
+    V: Send + 'static,
+{
+    /// serve proposes `command` for the client that sent `msg` and answers it with a `typ` reply
+    /// that carries whatever value the command was applied to, made into `data`.
+    ///
+    /// A replica that isn't the leader sends `forward` to the one that is instead, and relays its
+    /// reply, which `relay` picks the value out of. Only clients are forwarded, so `forward` is
+    /// `None` for requests from other nodes; that way nodes with different ideas of who leads
+    /// can't bounce a request between them.
+    pub fn serve<Q, F, D>(
+        &self,
+        msg: Message<Q>,
+        command: <P::Machine as StateMachine>::Command,
+        forward: Option<F>,
+        typ: &'static str,
+        data: fn(V) -> D,
+        relay: fn(Value) -> Option<V>,
+    ) -> anyhow::Result<Vec<Payload<ResponseBody<D>>>>
+    where
+        Q: Send + 'static,
+        F: Debug + Serialize,
+        D: Serialize + Send + 'static,
+    {
+        let leader = match self.propose(command) {
+            Ok(mut applied) => {
+                // Without peers it's applied straight away.
+                if let Ok(res) = applied.try_recv() {
+                    return Ok(vec![msg.reply(typ, res??.map(data))]);
+                }
+                let rpc = self.rpc.clone();
+                tokio::spawn(async move {
+                    let res = match tokio::time::timeout(CLIENT_TIMEOUT, applied).await {
+                        Ok(Ok(res)) => res.and_then(|out| out),
+                        Ok(Err(_)) => Err(ErrorBody::new(ErrorCode::Crash, "replica stopped")),
+                        Err(_) => Err(ErrorBody::new(
+                            ErrorCode::Timeout,
+                            "timed out waiting for the command to commit",
+                        )),
+                    };
+                    respond(&rpc, &msg, typ, res.map(|value| value.map(data)));
+                });
+                return Ok(vec![]);
+            }
+            Err(NotLeader { leader }) => leader,
+        };
+
+        let (Some(leader), Some(forward)) = (leader, forward) else {
+            return Err(ErrorBody::new(
+                ErrorCode::TemporarilyUnavailable,
+                "not the leader and no leader to forward to",
+            )
+            .into());
+        };
+        debug!("forwarding {:?} to {}", forward, leader);
+        let call = self.rpc.call::<_, Value>(
+            Payload {
+                src: self.id.clone(),
+                dest: leader,
+                body: forward,
+            },
+            CLIENT_TIMEOUT,
+        );
+        let rpc = self.rpc.clone();
+        tokio::spawn(async move {
+            let res = match call.await {
+                Ok(reply) => Ok(relay(reply.body).map(data)),
+                Err(e) => Err(ErrorBody::from(e)),
+            };
+            respond(&rpc, &msg, typ, res);
+        });
+        Ok(vec![])
+    }
+}
+
+// respond answers the client that sent `msg` from outside the handler.
+fn respond<Q, D: Serialize>(
+    rpc: &Rpc,
+    msg: &Message<Q>,
+    typ: &str,
+    res: Result<Option<D>, ErrorBody>,
+) {
+    let sent = match res {
+        Ok(data) => rpc.send(msg.reply(typ, data)),
+        Err(e) => rpc.send(msg.error(e)),
+    };
+    if let Err(e) = sent {
+        error!("failed to reply to {}: {:#}", msg.src, e);
+    }
+}
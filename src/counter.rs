@@ -1,9 +1,12 @@
-use crate::payload::{Payload, ResponseBody};
-use crate::{config, node, store};
+use crate::kv::{self, Kv};
+use crate::payload::{self, ErrorBody, ErrorCode, Message, Payload, ResponseBody};
+use crate::{config, node, rpc, store};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, SeekFrom, Write};
-use tracing::info;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, error, info};
 
 // Goals(s):
 // - Increment a single global counter
@@ -11,153 +14,393 @@ use tracing::info;
 //
 // Workload
 // - Adds a non-negative integer, called delta, to the counter.
+//
+// There are two ways of keeping the total that can be picked between at startup:
+//
+// - `kv` keeps it in `seq-kv` rather than the node's store so that every node sees the same
+//   counter. Adds are a read followed by a compare-and-swap that's retried until no other node
+//   got in between.
+// - `crdt` keeps a state-based G-Counter on every node: a count per node that only that node
+//   adds to, gossiped to every other node and merged by taking the larger count. Nothing but the
+//   nodes themselves is involved.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Mode {
+    #[default]
+    Kv,
+    Crdt,
+}
+
+// KEY is the `seq-kv` key the counter is kept under.
+const KEY: &str = "counter";
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
-struct ReadData {
-    value: u32,
+pub struct ReadData {
+    value: u64,
 }
 
-type ReadResponse = Payload<ResponseBody<ReadData>>;
-
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
-enum RequestBody {
-    Add {
-        msg_id: u32,
-        delta: u32,
-    },
-    Read {
-        msg_id: u32,
-    },
-    #[serde(other)]
-    Other,
+pub enum RequestBody {
+    Add { delta: u64 },
+    Read,
+    // Only sent between nodes in `crdt` mode.
+    Gossip { counts: GCounter },
 }
 
-pub fn listen<R, W, T, S>(node: &mut node::Node<S, T>, reader: R, writer: &mut W) -> Result<()>
-where
-    R: BufRead,
-    W: Write,
-    T: config::TimeSource,
+/// GCounter is a grow-only counter CRDT: how much each node has added, keyed by node id.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GCounter(HashMap<String, u64>);
+
+impl GCounter {
+    pub fn add(&mut self, node: &str, delta: u64) {
+        *self.0.entry(node.to_string()).or_default() += delta;
+    }
+
+    /// merge takes the larger count for every node, which makes it safe to apply the same or an
+    /// older state any number of times and in any order.
+    pub fn merge(&mut self, other: &GCounter) {
+        for (node, &count) in &other.0 {
+            let c = self.0.entry(node.clone()).or_default();
+            *c = (*c).max(count);
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+/// add adds `delta` to the counter.
+///
+/// A `cas` that fails because another node added in the meantime is retried with the new value.
+/// One that times out may or may not have been applied, so it's up to the caller whether to try
+/// again.
+pub async fn add(kv: &Kv, delta: u64) -> Result<(), kv::Error> {
+    loop {
+        let old = read_or_zero(kv).await?;
+        match kv.cas(&KEY, &old, &(old + delta), true).await {
+            Ok(()) => return Ok(()),
+            Err(kv::Error::PreconditionFailed) => debug!("counter moved from {}, retrying", old),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// read returns the counter's current value.
+///
+/// `seq-kv` is allowed to serve a stale read, so the value is only returned once a `cas` that
+/// leaves it unchanged confirms it's the latest one.
+pub async fn read(kv: &Kv) -> Result<u64, kv::Error> {
+    loop {
+        let v = read_or_zero(kv).await?;
+        match kv.cas(&KEY, &v, &v, true).await {
+            Ok(()) => return Ok(v),
+            Err(kv::Error::PreconditionFailed) => debug!("read a stale {}, retrying", v),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn read_or_zero(kv: &Kv) -> Result<u64, kv::Error> {
+    match kv.read(&KEY).await {
+        Ok(v) => Ok(v),
+        // Nobody has added anything yet.
+        Err(kv::Error::KeyDoesNotExist) => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// start_gossip gossips `state` from `node` to every one of its peers, see [`spawn_gossip`]. The
+/// node has to have been initialized.
+pub(crate) fn start_gossip<S, T, C, B>(
+    node: &node::Node<S, T>,
+    interval: Duration,
+    state: &Arc<Mutex<C>>,
+    body: fn(C) -> B,
+) where
     S: store::Store,
+    T: config::TimeSource,
+    C: Clone + Send + 'static,
+    B: Serialize + 'static,
+{
+    spawn_gossip(
+        node.id.clone(),
+        node.world.keys().cloned().collect(),
+        node.rpc.clone(),
+        interval,
+        state.clone(),
+        body,
+    );
+}
+
+/// spawn_gossip sends a copy of `state`, wrapped in a request by `body`, to every peer every
+/// `interval` until the node stops. A lost gossip is made up for by the next one, so nothing is
+/// acknowledged.
+pub(crate) fn spawn_gossip<C, B>(
+    src: String,
+    peers: Vec<String>,
+    rpc: rpc::Rpc,
+    interval: Duration,
+    state: Arc<Mutex<C>>,
+    body: fn(C) -> B,
+) where
+    C: Clone + Send + 'static,
+    B: Serialize + 'static,
 {
-    // https://docs.rs/serde_json/latest/serde_json/fn.from_reader.html
-    // from_reader will read to end of deserialized object
-    let msg: Payload<RequestBody> = serde_json::from_reader(reader)?;
-    info!(">> input: {:?}", msg);
-    match msg.body {
-        RequestBody::Add { msg_id, delta } => {
-            let mut buf = [0u8; 4];
-            node.store.seek(SeekFrom::Start(0))?;
-            let _ = node.store.read(&mut buf)?;
-
-            // TODO: The lock must extend around this
-            // Reading Ch 55 (p1117) of The Linux System Interface on "File Locking"
-            // describes this exact problem. `flock()` (full file lock) and `fcntl()` (file region
-            // lock).
-            //
-            // - be careful using `stdio` for reading/writing as user-space buffers may not be
-            // synced with locks. Alternatively, you must ensure you flush the buffer immediately after taking and
-            // before releasing the lock.
-            //
-            // https://github.com/rust-lang/libs-team/issues/412
-            let old = u32::from_le_bytes(buf);
-            let new = old + delta;
-            node.store.seek(SeekFrom::Start(0))?;
-            node.store.write_all(&new.to_le_bytes())?;
-
-            io::to_writer(
-                writer,
-                &Payload {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: ResponseBody::<()> {
-                        typ: "add_ok".to_string(),
-                        in_reply_to: msg_id,
-                        data: None,
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            tick.tick().await;
+            let data = body(
+                state
+                    .lock()
+                    .expect("failed to take gossip state lock")
+                    .clone(),
+            );
+            for peer in &peers {
+                let msg = Payload {
+                    src: src.clone(),
+                    dest: peer.clone(),
+                    body: payload::RequestBody {
+                        msg_id: rpc.next_msg_id(),
+                        data: &data,
                     },
-                },
-            )?;
+                };
+                if let Err(e) = rpc.send(msg) {
+                    info!("stopping gossip: {:#}", e);
+                    return;
+                }
+            }
         }
+    });
+}
 
-        RequestBody::Read { msg_id } => {
-            let mut buf = [0u8; 4];
-            node.store.seek(SeekFrom::Start(0))?;
-            let _ = node.store.read(&mut buf)?;
-            let v = u32::from_le_bytes(buf);
-
-            io::to_writer(
-                writer,
-                &ReadResponse {
-                    src: msg.dest,
-                    dest: msg.src,
-                    body: ResponseBody {
-                        typ: "read_ok".to_string(),
-                        in_reply_to: msg_id,
-                        data: Some(ReadData { value: v }),
-                    },
-                },
-            )?;
+pub struct Counter {
+    mode: Mode,
+    state: Arc<Mutex<GCounter>>,
+    interval: Duration,
+}
+
+impl Counter {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            state: Default::default(),
+            interval: Duration::from_millis(500),
         }
+    }
+
+    /// with_interval sets how often the state is gossiped in `crdt` mode.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    fn handle_crdt(
+        &mut self,
+        node_id: &str,
+        msg: Message<RequestBody>,
+    ) -> Vec<Payload<ResponseBody<ReadData>>> {
+        let mut state = self.state.lock().expect("failed to take counter lock");
+        match &msg.body.data {
+            RequestBody::Add { delta } => {
+                state.add(node_id, *delta);
+                vec![msg.reply("add_ok", None)]
+            }
+            RequestBody::Read => vec![msg.reply(
+                "read_ok",
+                Some(ReadData {
+                    value: state.value(),
+                }),
+            )],
+            // Gossip is fire and forget.
+            RequestBody::Gossip { counts } => {
+                state.merge(counts);
+                vec![]
+            }
+        }
+    }
+}
+
+impl<S, T> node::Handler<S, T> for Counter
+where
+    T: config::TimeSource,
+    S: store::Store,
+{
+    type Request = RequestBody;
+    type Response = ResponseBody<ReadData>;
+
+    // Gossip starts once the node knows who its peers are.
+    fn init(&mut self, node: &mut node::Node<S, T>) -> Result<()> {
+        if self.mode == Mode::Crdt {
+            start_gossip(node, self.interval, &self.state, |counts| {
+                RequestBody::Gossip { counts }
+            });
+        }
+        Ok(())
+    }
 
-        RequestBody::Other => {
-            info!("other: {:?}", msg);
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        if self.mode == Mode::Crdt {
+            return Ok(self.handle_crdt(&node.id, msg));
         }
-    };
-    Ok(())
+
+        // Talking to `seq-kv` means waiting on replies, which can't happen on the dispatch loop.
+        let kv = node.kv(kv::Service::SeqKv);
+        let rpc = node.rpc.clone();
+        tokio::spawn(async move {
+            let reply = match msg.body.data {
+                RequestBody::Add { delta } => add(&kv, delta)
+                    .await
+                    .map(|()| msg.reply::<ReadData>("add_ok", None))
+                    .map_err(ErrorBody::from),
+                RequestBody::Read => read(&kv)
+                    .await
+                    .map(|value| msg.reply("read_ok", Some(ReadData { value })))
+                    .map_err(ErrorBody::from),
+                RequestBody::Gossip { .. } => Err(ErrorBody::new(
+                    ErrorCode::NotSupported,
+                    "gossip is only used in crdt mode",
+                )),
+            };
+            let sent = match reply {
+                Ok(reply) => rpc.send(reply),
+                Err(e) => rpc.send(msg.error(e)),
+            };
+            if let Err(e) = sent {
+                error!("failed to reply to {}: {:#}", msg.src, e);
+            }
+        });
+        Ok(vec![])
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::node::Sender;
+    use crate::rpc::Rpc;
     use std::{io::Cursor, time};
+    use tokio::sync::mpsc;
 
     #[test]
-    fn counter() {
-        let test_cases = vec![
-            (
-                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":1}}
-"#,
-                r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":1,"value":0}}
-"#,
-            ),
-            (
-                r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":2}}
-"#,
-                r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":2}}
-"#,
-            ),
-            (
-                r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":2}}
-"#,
-                r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":3}}
-"#,
-            ),
-            (
-                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}
-"#,
-                r#"{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":4,"value":4}}
-"#,
-            ),
-        ];
-
-        let buf: Vec<u8> = Vec::new();
-        let s = store::MemoryStore::new(buf).expect("failed to create store");
+    fn merge() {
+        let mut a = GCounter::default();
+        a.add("n1", 3);
+        a.add("n1", 2);
+        let mut b = GCounter::default();
+        b.add("n1", 1);
+        b.add("n2", 4);
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 9);
+
+        // Merging something that's already been merged changes nothing.
+        ab.merge(&b);
+        assert_eq!(ab.value(), 9);
+    }
+
+    #[tokio::test]
+    async fn crdt() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}
+{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":2}}
+{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":1,"counts":{"n1":1,"n2":5}}}
+{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":3}}
+{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":2,"counts":{"n2":4}}}
+{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":2}}
+{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":3}}
+{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":4,"value":10}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
         let cfg = config::Config::<config::MockTime>::new(config::MockTime {
             now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
         })
         .expect("failed to get config");
         let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
 
-        for (input, expected) in test_cases {
-            // Necessary to implement Read trait on BufReader for bytes
-            let mut actual: Vec<u8> = Vec::new();
-            let mut write_cursor = Cursor::new(&mut actual);
-            let read_cursor = Cursor::new(input.as_bytes());
+        // Nothing is gossiped for as long as the test runs.
+        let counter = Counter::new(Mode::Crdt).with_interval(Duration::from_secs(3600));
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(Cursor::new(input.as_bytes()), &mut actual, counter)
+            .await
+            .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn gossip() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let state: Arc<Mutex<GCounter>> = Default::default();
+        state.lock().unwrap().add("n1", 7);
 
-            listen(&mut n, read_cursor, &mut write_cursor).expect("listen failed");
-            assert_eq!(String::from_utf8(actual).unwrap().trim(), expected.trim());
+        spawn_gossip(
+            "n1".to_string(),
+            vec!["n2".to_string(), "n3".to_string()],
+            rpc,
+            Duration::from_millis(10),
+            state,
+            |counts| RequestBody::Gossip { counts },
+        );
+        let mut dests = Vec::new();
+        for _ in 0..2 {
+            let msg = rx.recv().await.expect("expected gossip");
+            assert_eq!(msg.body["type"], "gossip");
+            assert_eq!(msg.body["counts"], serde_json::json!({"n1": 7}));
+            dests.push(msg.dest);
         }
+        dests.sort();
+        assert_eq!(dests, vec!["n2", "n3"]);
+    }
+
+    #[tokio::test]
+    async fn counter() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let service = kv::fake::serve(rpc.clone(), rx);
+
+        let nodes: Vec<Kv> = ["n1", "n2", "n3"]
+            .into_iter()
+            .map(|n| Kv::new(rpc.clone(), n.to_string(), kv::Service::SeqKv))
+            .collect();
+        assert_eq!(read(&nodes[0]).await.expect("read failed"), 0);
+
+        // Every node adds at the same time, so most of the `cas`es have to be retried.
+        let adds: Vec<_> = nodes
+            .iter()
+            .cloned()
+            .enumerate()
+            .flat_map(|(i, kv)| {
+                (1..=10).map(move |delta| {
+                    let kv = kv.clone();
+                    tokio::spawn(async move { add(&kv, delta * (i as u64 + 1)).await })
+                })
+            })
+            .collect();
+        for a in adds {
+            a.await.unwrap().expect("add failed");
+        }
+
+        for kv in &nodes {
+            assert_eq!(read(kv).await.expect("read failed"), 55 * 6);
+        }
+
+        service.abort();
     }
 }
@@ -1,59 +1,44 @@
-use crate::payload::{Payload, RequestBody, ResponseBody};
+use crate::payload::{Message, Payload, ResponseBody};
 use crate::{config, node, store};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::{BufRead, Write};
-use tracing::info;
 
 #[derive(Serialize, Deserialize, Debug)]
-struct EchoData {
+pub struct EchoData {
     echo: String,
 }
-type EchoRequest = Payload<RequestBody<EchoData>>;
-type EchoResponse = Payload<ResponseBody<EchoData>>;
 
-// I use "untagged" in the following because the type tag differs based on the message.
-// I could split the Init message into a separate enum so that I could infer
-// the type based on different internal fields in the message body.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
-enum Message {
-    Echo(EchoRequest),
-    Other(HashMap<String, serde_json::Value>),
+pub enum RequestBody {
+    Echo(EchoData),
 }
 
-pub fn listen<R, W, T, S>(_node: &mut node::Node<S, T>, reader: R, writer: &mut W) -> Result<()>
+pub struct Echo;
+
+impl<S, T> node::Handler<S, T> for Echo
 where
-    R: BufRead,
-    W: Write,
     T: config::TimeSource,
     S: store::Store,
 {
-    // https://docs.rs/serde_json/latest/serde_json/fn.from_reader.html
-    // from_reader will read to end of deserialized object
-    let msg: Message = serde_json::from_reader(reader)?;
-    match msg {
-        Message::Echo(Payload { src, dest, body }) => io::to_writer(
-            writer,
-            &EchoResponse {
-                src: dest,
-                dest: src,
-                body: ResponseBody {
-                    typ: "echo_ok".to_string(),
-                    in_reply_to: body.msg_id,
-                    data: Some(EchoData {
-                        echo: body.data.expect("failed to get echo body").echo,
-                    }),
-                },
-            },
-        )?,
-        Message::Other(m) => {
-            info!("other: {:?}", m);
+    type Request = RequestBody;
+    type Response = ResponseBody<EchoData>;
+
+    fn handle(
+        &mut self,
+        _node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        match &msg.body.data {
+            RequestBody::Echo(data) => Ok(vec![msg.reply(
+                "echo_ok",
+                Some(EchoData {
+                    echo: data.echo.clone(),
+                }),
+            )]),
         }
-    };
-    Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -61,8 +46,8 @@ mod tests {
     use super::*;
     use std::{io::Cursor, time};
 
-    #[test]
-    fn echo() {
+    #[tokio::test]
+    async fn echo() {
         let test_cases = vec![
             (
                 r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"Please echo 35"}}
@@ -79,20 +64,20 @@ mod tests {
         ];
 
         let buf: Vec<u8> = Vec::new();
-        let mut s = store::MemoryStore::new(buf).expect("failed to create store");
+        let s = store::MemoryStore::new(buf).expect("failed to create store");
         let cfg = config::Config::<config::MockTime>::new(config::MockTime {
             now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
         })
         .expect("failed to get config");
-        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(&mut s, cfg);
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
 
         for (input, expected) in test_cases {
-            // Necessary to implement Read trait on BufReader for bytes
             let mut vec: Vec<u8> = Vec::new();
-            let mut write_cursor = Cursor::new(&mut vec);
             let read_cursor = Cursor::new(input.as_bytes());
 
-            listen(&mut n, read_cursor, &mut write_cursor).expect("listen failed");
+            n.run(read_cursor, &mut vec, Echo)
+                .await
+                .expect("run failed");
 
             assert_eq!(String::from_utf8(vec).unwrap().trim(), expected.trim());
         }
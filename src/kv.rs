@@ -0,0 +1,348 @@
+use crate::payload::{ErrorBody, ErrorCode, Payload};
+use crate::rpc::{self, Rpc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+// Maelstrom runs a few key/value stores as services that any node can talk to like it would any
+// other node:
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md
+//
+// - `seq-kv` is sequentially consistent, so a read may be stale but never goes back in time.
+// - `lin-kv` is linearizable.
+// - `lww-kv` is last-write-wins, i.e., eventually consistent.
+//
+// All three take the same `read`, `write` and `cas` messages.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    SeqKv,
+    LinKv,
+    LwwKv,
+}
+
+impl Service {
+    /// name is the node id the service is addressed by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::SeqKv => "seq-kv",
+            Service::LinKv => "lin-kv",
+            Service::LwwKv => "lww-kv",
+        }
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    KeyDoesNotExist,
+    // The value wasn't `from` when a `cas` was applied.
+    PreconditionFailed,
+    Rpc(rpc::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::KeyDoesNotExist => write!(f, "key does not exist"),
+            Error::PreconditionFailed => write!(f, "precondition failed"),
+            Error::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<rpc::Error> for Error {
+    fn from(e: rpc::Error) -> Self {
+        match e {
+            rpc::Error::Reply(e) if e.code == ErrorCode::KeyDoesNotExist => Error::KeyDoesNotExist,
+            rpc::Error::Reply(e) if e.code == ErrorCode::PreconditionFailed => {
+                Error::PreconditionFailed
+            }
+            e => Error::Rpc(e),
+        }
+    }
+}
+
+impl From<Error> for ErrorBody {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::KeyDoesNotExist => ErrorBody::new(ErrorCode::KeyDoesNotExist, e.to_string()),
+            Error::PreconditionFailed => {
+                ErrorBody::new(ErrorCode::PreconditionFailed, e.to_string())
+            }
+            Error::Rpc(e) => e.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Request<'a, K, V> {
+    Read {
+        key: &'a K,
+    },
+    Write {
+        key: &'a K,
+        value: &'a V,
+    },
+    Cas {
+        key: &'a K,
+        from: &'a V,
+        to: &'a V,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadOk<V> {
+    value: V,
+}
+
+/// Kv is a cloneable client for one of Maelstrom's key/value services.
+///
+/// Keys and values are anything that serializes to JSON, which is all the services care about.
+#[derive(Debug, Clone)]
+pub struct Kv {
+    rpc: Rpc,
+    src: String,
+    service: Service,
+    timeout: Duration,
+}
+
+impl Kv {
+    /// new creates a client that sends requests as `src`, which should be the node's id.
+    pub fn new(rpc: Rpc, src: String, service: Service) -> Self {
+        Self {
+            rpc,
+            src,
+            service,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// with_timeout sets how long to wait for the service to reply before giving up.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn service(&self) -> Service {
+        self.service
+    }
+
+    pub async fn read<K, V>(&self, key: &K) -> Result<V, Error>
+    where
+        K: Serialize,
+        V: DeserializeOwned,
+    {
+        let reply = self.call::<_, (), ReadOk<V>>(Request::Read { key }).await?;
+        Ok(reply.value)
+    }
+
+    pub async fn write<K, V>(&self, key: &K, value: &V) -> Result<(), Error>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.call::<_, _, Value>(Request::Write { key, value })
+            .await?;
+        Ok(())
+    }
+
+    /// cas sets `key` to `to` if it's currently `from`.
+    ///
+    /// With `create_if_not_exists` a key that doesn't exist yet is created with `to` rather than
+    /// failing with [`Error::KeyDoesNotExist`].
+    pub async fn cas<K, V>(
+        &self,
+        key: &K,
+        from: &V,
+        to: &V,
+        create_if_not_exists: bool,
+    ) -> Result<(), Error>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.call::<_, _, Value>(Request::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn call<K, V, R>(&self, body: Request<'_, K, V>) -> Result<R, Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        R: DeserializeOwned,
+    {
+        let msg = Payload {
+            src: self.src.clone(),
+            dest: self.service.name().to_string(),
+            body,
+        };
+        let reply = self.rpc.call::<_, R>(msg, self.timeout).await?;
+        Ok(reply.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Sender;
+    use tokio::sync::mpsc;
+
+    fn reply(body: Value) -> Payload<Value> {
+        Payload {
+            src: "seq-kv".to_string(),
+            dest: "n1".to_string(),
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn read() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let kv = Kv::new(rpc.clone(), "n1".to_string(), Service::SeqKv);
+
+        let read = tokio::spawn(async move { kv.read::<_, u64>(&"counter").await });
+        let sent = rx.recv().await.expect("expected a read");
+        assert_eq!(sent.dest, "seq-kv");
+        assert_eq!(
+            sent.body,
+            serde_json::json!({"msg_id": 1, "type": "read", "key": "counter"})
+        );
+
+        rpc.resolve(reply(
+            serde_json::json!({"type": "read_ok", "in_reply_to": 1, "value": 42}),
+        ));
+        assert_eq!(read.await.unwrap().expect("read failed"), 42);
+    }
+
+    #[tokio::test]
+    async fn read_missing() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let kv = Kv::new(rpc.clone(), "n1".to_string(), Service::SeqKv);
+
+        let read = tokio::spawn(async move { kv.read::<_, u64>(&"counter").await });
+        rx.recv().await.expect("expected a read");
+
+        rpc.resolve(reply(serde_json::json!({
+            "type": "error", "in_reply_to": 1, "code": 20, "text": "key does not exist"
+        })));
+        assert!(matches!(read.await.unwrap(), Err(Error::KeyDoesNotExist)));
+    }
+
+    #[tokio::test]
+    async fn cas() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let kv = Kv::new(rpc.clone(), "n1".to_string(), Service::LinKv);
+
+        let cas = {
+            let kv = kv.clone();
+            tokio::spawn(async move { kv.cas(&"counter", &0, &1, true).await })
+        };
+        let sent = rx.recv().await.expect("expected a cas");
+        assert_eq!(sent.dest, "lin-kv");
+        assert_eq!(
+            sent.body,
+            serde_json::json!({
+                "msg_id": 1, "type": "cas", "key": "counter", "from": 0, "to": 1,
+                "create_if_not_exists": true
+            })
+        );
+        rpc.resolve(reply(
+            serde_json::json!({"type": "cas_ok", "in_reply_to": 1}),
+        ));
+        cas.await.unwrap().expect("cas failed");
+
+        let cas = tokio::spawn(async move { kv.cas(&"counter", &0, &1, false).await });
+        let sent = rx.recv().await.expect("expected a cas");
+        assert!(sent.body.get("create_if_not_exists").is_none());
+        rpc.resolve(reply(serde_json::json!({
+            "type": "error", "in_reply_to": 2, "code": 22, "text": "expected 0, had 1"
+        })));
+        assert!(matches!(cas.await.unwrap(), Err(Error::PreconditionFailed)));
+    }
+}
+
+// An in-memory stand-in for the services for tests that need something to talk to. Only the
+// workloads built on the services use it.
+#[cfg(all(
+    test,
+    any(
+        feature = "counter",
+        feature = "list_append",
+        feature = "replicated_log"
+    )
+))]
+pub(crate) mod fake {
+    use crate::payload::Payload;
+    use crate::rpc::Rpc;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+    use tokio::task::JoinHandle;
+
+    fn error(code: u32) -> Value {
+        json!({"type": "error", "code": code})
+    }
+
+    /// serve answers every request sent on `rx` the way a linearizable service would, until
+    /// `rx` is closed.
+    pub(crate) fn serve(
+        rpc: Rpc,
+        mut rx: mpsc::UnboundedReceiver<Payload<Value>>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut data: HashMap<String, Value> = HashMap::new();
+            while let Some(msg) = rx.recv().await {
+                let req = &msg.body;
+                let key = req["key"].to_string();
+                let mut reply = match req["type"].as_str() {
+                    Some("read") => match data.get(&key) {
+                        Some(v) => json!({"type": "read_ok", "value": v}),
+                        None => error(20),
+                    },
+                    Some("write") => {
+                        data.insert(key, req["value"].clone());
+                        json!({"type": "write_ok"})
+                    }
+                    Some("cas") => match data.get(&key) {
+                        Some(v) if *v != req["from"] => error(22),
+                        None if req["create_if_not_exists"] != true => error(20),
+                        _ => {
+                            data.insert(key, req["to"].clone());
+                            json!({"type": "cas_ok"})
+                        }
+                    },
+                    _ => error(10),
+                };
+                reply["in_reply_to"] = req["msg_id"].clone();
+                rpc.resolve(Payload {
+                    src: msg.dest,
+                    dest: msg.src,
+                    body: reply,
+                });
+            }
+        })
+    }
+}
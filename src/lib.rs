@@ -1,21 +1,49 @@
 // Core modules used by all binaries
 pub mod config;
+pub mod kv;
 pub mod node;
+pub mod outbox;
 pub mod payload;
+pub mod persist;
+pub mod rpc;
 pub mod store;
 
 // Feature-gated modules
 #[cfg(feature = "broadcast")]
 pub mod broadcast;
 
+#[cfg(any(feature = "raft", feature = "paxos"))]
+pub mod consensus;
+
 #[cfg(feature = "counter")]
 pub mod counter;
 
 #[cfg(feature = "echo")]
 pub mod echo;
 
+#[cfg(feature = "lin_kv")]
+pub mod lin_kv;
+
+#[cfg(feature = "list_append")]
+pub mod list_append;
+
+#[cfg(feature = "lock")]
+pub mod lock;
+
+#[cfg(feature = "paxos")]
+pub mod paxos;
+
+#[cfg(feature = "pn_counter")]
+pub mod pn_counter;
+
+#[cfg(feature = "raft")]
+pub mod raft;
+
 #[cfg(feature = "unique")]
 pub mod unique;
 
 #[cfg(feature = "replicated_log")]
 pub mod replicated_log;
+
+#[cfg(feature = "txn")]
+pub mod txn;
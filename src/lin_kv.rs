@@ -0,0 +1,323 @@
+use crate::consensus::{Protocol, Replica, RequestBody, StateMachine};
+use crate::payload::{ErrorBody, ErrorCode, Message, Payload, ResponseBody};
+use crate::{config, node, store};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Goals(s):
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-lin-kv
+// - Serve a linearizable key/value store of `read`, `write` and `cas` operations.
+// - Stay available as long as a majority of nodes can reach each other.
+//
+// Every operation, reads included, is a command that's replicated by a consensus protocol, and
+// it's applied to the registers once it's committed. The protocol is picked at startup, either
+// Raft (`raft.rs`) or Multi-Paxos (`paxos.rs`), so that they can be compared on the same workload.
+// Only the leader can propose commands, so every other node forwards clients to the one it last
+// heard was the leader.
+//
+// Nodes start the protocol when they're initialized. With a directory to keep it in, which only
+// Raft supports, each node's state is kept under `<dir>/<node id>/` and read back on `init`, so a
+// node that's killed and restarted picks up where it left off.
+
+/// Command is an operation on the registers. It's both what clients send and what's replicated.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Read { key: Value },
+    Write { key: Value, value: Value },
+    Cas { key: Value, from: Value, to: Value },
+}
+
+impl Command {
+    fn reply_type(&self) -> &'static str {
+        match self {
+            Command::Read { .. } => "read_ok",
+            Command::Write { .. } => "write_ok",
+            Command::Cas { .. } => "cas_ok",
+        }
+    }
+}
+
+// R is the protocol's reply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ResponseData<R> {
+    Read { value: Value },
+    Consensus(R),
+}
+
+/// Registers is the state machine: a register per key, which can hold any JSON value.
+#[derive(Debug, Default)]
+pub struct Registers {
+    // Keyed by the key's JSON, since keys can be any JSON value too.
+    values: HashMap<String, Value>,
+}
+
+impl StateMachine for Registers {
+    type Command = Command;
+    // The value that was read, if the command was a read.
+    type Output = Result<Option<Value>, ErrorBody>;
+    type Snapshot = HashMap<String, Value>;
+
+    fn apply(&mut self, command: &Command) -> Self::Output {
+        match command {
+            Command::Read { key } => match self.values.get(&key.to_string()) {
+                Some(value) => Ok(Some(value.clone())),
+                None => Err(not_found(key)),
+            },
+            Command::Write { key, value } => {
+                self.values.insert(key.to_string(), value.clone());
+                Ok(None)
+            }
+            Command::Cas { key, from, to } => {
+                let Some(current) = self.values.get_mut(&key.to_string()) else {
+                    return Err(not_found(key));
+                };
+                if current != from {
+                    return Err(ErrorBody::new(
+                        ErrorCode::PreconditionFailed,
+                        format!("expected {} but found {}", from, current),
+                    ));
+                }
+                *current = to.clone();
+                Ok(None)
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.values.clone()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        self.values = snapshot;
+    }
+}
+
+fn not_found(key: &Value) -> ErrorBody {
+    ErrorBody::new(
+        ErrorCode::KeyDoesNotExist,
+        format!("key {} does not exist", key),
+    )
+}
+
+/// LinKv serves the `lin-kv` workload from [`Registers`] replicated with the protocol `P`.
+pub struct LinKv<P: Protocol<Machine = Registers>> {
+    // Where the protocol's state is kept, if anywhere.
+    dir: Option<PathBuf>,
+    // Started on `init`, once the node knows who its peers are.
+    replica: Option<Replica<P>>,
+}
+
+impl<P: Protocol<Machine = Registers>> Default for LinKv<P> {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            replica: None,
+        }
+    }
+}
+
+impl<P: Protocol<Machine = Registers>> LinKv<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// with_dir keeps the protocol's state under `dir` so that it survives the node being
+    /// restarted.
+    pub fn with_dir(mut self, dir: PathBuf) -> Self {
+        self.dir = Some(dir);
+        self
+    }
+}
+
+impl<S, T, P> node::Handler<S, T> for LinKv<P>
+where
+    T: config::TimeSource,
+    S: store::Store,
+    P: Protocol<Machine = Registers>,
+{
+    type Request = RequestBody<Box<Command>, P::Request>;
+    type Response = ResponseBody<ResponseData<P::Reply>>;
+
+    fn init(&mut self, node: &mut node::Node<S, T>) -> Result<()> {
+        let dir = self.dir.as_ref().map(|dir| dir.join(&node.id));
+        let replica = Replica::start(
+            node.id.clone(),
+            node.world.keys().cloned().collect(),
+            Registers::default(),
+            dir.as_deref(),
+            node.rpc.clone(),
+        )
+        .context("failed to start replica")?;
+        self.replica = Some(replica);
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<Self::Request>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        let Some(replica) = &self.replica else {
+            anyhow::bail!("received {:?} before init", msg.body.data);
+        };
+
+        let command = match &msg.body.data {
+            RequestBody::Consensus(req) => {
+                let (typ, reply) = replica.handle(req);
+                return Ok(vec![msg.reply(typ, Some(ResponseData::Consensus(reply)))]);
+            }
+            RequestBody::Client(command) => (**command).clone(),
+        };
+        let forward = (!node.world.contains_key(&msg.src)).then(|| command.clone());
+        let typ = command.reply_type();
+        replica.serve(
+            msg,
+            command,
+            forward,
+            typ,
+            |value| ResponseData::Read { value },
+            |reply| reply.get("value").cloned(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paxos::Paxos;
+    use crate::raft::Raft;
+    use serde_json::json;
+    use std::{io::Cursor, time};
+
+    #[test]
+    fn registers() {
+        let mut r = Registers::default();
+        let e = r.apply(&Command::Read { key: json!(1) }).unwrap_err();
+        assert_eq!(e.code, ErrorCode::KeyDoesNotExist);
+
+        r.apply(&Command::Write {
+            key: json!(1),
+            value: json!(2),
+        })
+        .unwrap();
+        let e = r
+            .apply(&Command::Cas {
+                key: json!(1),
+                from: json!(3),
+                to: json!(4),
+            })
+            .unwrap_err();
+        assert_eq!(e.code, ErrorCode::PreconditionFailed);
+        r.apply(&Command::Cas {
+            key: json!(1),
+            from: json!(2),
+            to: json!(4),
+        })
+        .unwrap();
+        assert_eq!(
+            r.apply(&Command::Read { key: json!(1) }),
+            Ok(Some(json!(4)))
+        );
+    }
+
+    // single_node runs a node on its own, which leads straight away, with the protocol `P`.
+    async fn single_node<P: Protocol<Machine = Registers>>() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2,"key":1}}
+{"src":"c1","dest":"n1","body":{"type":"write","msg_id":3,"key":1,"value":5}}
+{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":4,"key":1,"from":4,"to":6}}
+{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":5,"key":1,"from":5,"to":6}}
+{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6,"key":1}}
+{"src":"c1","dest":"n1","body":{"type":"delete","msg_id":7,"key":1}}
+{"src":"c1","dest":"n1","body":{"type":"write","msg_id":8,"key":1}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":2,"code":20,"text":"key 1 does not exist"}}
+{"src":"n1","dest":"c1","body":{"type":"write_ok","in_reply_to":3}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":4,"code":22,"text":"expected 4 but found 5"}}
+{"src":"n1","dest":"c1","body":{"type":"cas_ok","in_reply_to":5}}
+{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":6,"value":6}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":7,"code":10,"text":"unknown variant `delete`, expected a client or consensus request"}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":8,"code":12,"text":"missing field `value`"}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(
+            Cursor::new(input.as_bytes()),
+            &mut actual,
+            LinKv::<P>::new(),
+        )
+        .await
+        .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn single_node_raft() {
+        single_node::<Raft<Registers>>().await;
+    }
+
+    #[tokio::test]
+    async fn single_node_paxos() {
+        single_node::<Paxos<Registers>>().await;
+    }
+
+    #[tokio::test]
+    async fn restart() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let run = async |input: &str| {
+            let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+            let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+                now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+            })
+            .expect("failed to get config");
+            let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+            let mut actual: Vec<u8> = Vec::new();
+            n.run(
+                Cursor::new(input.as_bytes()),
+                &mut actual,
+                LinKv::<Raft<Registers>>::new().with_dir(dir.path().to_path_buf()),
+            )
+            .await
+            .expect("run failed");
+            String::from_utf8(actual).unwrap()
+        };
+
+        let init = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+"#;
+        run(&format!(
+            "{}{}",
+            init,
+            r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":2,"key":"k","value":[1,2]}}
+"#
+        ))
+        .await;
+
+        // The write is replayed from the log before `init_ok`.
+        let actual = run(&format!(
+            "{}{}",
+            init,
+            r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":2,"key":"k"}}
+"#
+        ))
+        .await;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":2,"value":[1,2]}}
+"#;
+        assert_eq!(actual, expected);
+        assert!(dir.path().join("n1").join("state.json").exists());
+    }
+}
@@ -0,0 +1,277 @@
+use crate::kv::{self, Kv};
+use crate::payload::{ErrorBody, ErrorCode, Message, Payload, ResponseBody};
+use crate::{config, node, store};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, error};
+
+// Goals(s):
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-txn-list-append
+// - Run transactions of `append` and read micro-operations against lists of integers.
+// - Appends leave a trace of the order they were applied in, which lets Elle check for far more
+//   anomalies than the rw-register workload can, up to strict serializability.
+//
+// A transaction reads from a snapshot taken when it starts and sees its own appends on top of
+// it. Its appends are then committed all at once. Where the lists are kept is picked at startup:
+//
+// - `local` keeps the lists on the node, which is only correct with a single node. Transactions
+//   run one at a time on the dispatch loop, so the latest lists are every transaction's snapshot
+//   and no older versions are kept.
+// - `kv` keeps the whole database as a single value in `lin-kv`. A transaction reads it as its
+//   snapshot and commits with a compare-and-swap from that snapshot, so it only commits if
+//   nothing else committed in the meantime and fails with `txn-conflict` otherwise.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Mode {
+    Local,
+    #[default]
+    Kv,
+}
+
+// ROOT is the `lin-kv` key the database is kept under in `kv` mode.
+const ROOT: &str = "root";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "append")]
+    Append,
+}
+
+/// Operand is what a micro-operation acts on: the element to append, or the list that was read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Operand {
+    Element(u64),
+    List(Vec<u64>),
+}
+
+/// MicroOp is one step of a transaction, `["r", key, null]` or `["append", key, element]`. A
+/// read's list is filled in when it's run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MicroOp(pub Op, pub u64, pub Option<Operand>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnData {
+    txn: Vec<MicroOp>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RequestBody {
+    Txn(TxnData),
+}
+
+// run executes `txn` against the lists `snapshot` returns, filling in its reads, and returns
+// every list it appended to as it is once the appends are applied.
+fn run(txn: &mut [MicroOp], snapshot: impl Fn(u64) -> Vec<u64>) -> BTreeMap<u64, Vec<u64>> {
+    let mut written: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for MicroOp(op, key, operand) in txn.iter_mut() {
+        match (op, &operand) {
+            (Op::Read, _) => {
+                let list = written.get(key).cloned().unwrap_or_else(|| snapshot(*key));
+                *operand = Some(Operand::List(list));
+            }
+            (Op::Append, Some(Operand::Element(v))) => written
+                .entry(*key)
+                .or_insert_with(|| snapshot(*key))
+                .push(*v),
+            // Anything else isn't something that can be appended, so it's left alone.
+            (Op::Append, _) => debug!("ignoring append of {:?} to {}", operand, key),
+        }
+    }
+    written
+}
+
+/// Lists keeps the latest committed version of every list.
+#[derive(Debug, Default)]
+pub struct Lists {
+    lists: HashMap<u64, Vec<u64>>,
+}
+
+impl Lists {
+    /// read returns `key`'s list.
+    pub fn read(&self, key: u64) -> Vec<u64> {
+        self.lists.get(&key).cloned().unwrap_or_default()
+    }
+
+    /// commit replaces the lists in `lists`.
+    pub fn commit(&mut self, lists: BTreeMap<u64, Vec<u64>>) {
+        self.lists.extend(lists);
+    }
+}
+
+/// ListAppend serves the `txn-list-append` workload in whichever [`Mode`] it's created with.
+#[derive(Debug, Default)]
+pub struct ListAppend {
+    mode: Mode,
+    // Only used in `local` mode.
+    lists: Lists,
+}
+
+impl ListAppend {
+    pub fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            lists: Lists::default(),
+        }
+    }
+}
+
+impl<S, T> node::Handler<S, T> for ListAppend
+where
+    T: config::TimeSource,
+    S: store::Store,
+{
+    type Request = RequestBody;
+    type Response = ResponseBody<TxnData>;
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        let RequestBody::Txn(data) = &msg.body.data;
+        let mut txn = data.txn.clone();
+
+        match self.mode {
+            Mode::Local => {
+                let written = run(&mut txn, |key| self.lists.read(key));
+                if !written.is_empty() {
+                    self.lists.commit(written);
+                }
+                Ok(vec![msg.reply("txn_ok", Some(TxnData { txn }))])
+            }
+            Mode::Kv => {
+                // Waiting on the service can't happen on the dispatch loop.
+                let lin = node.kv(kv::Service::LinKv);
+                let rpc = node.rpc.clone();
+                tokio::spawn(async move {
+                    let sent = match commit(&lin, &mut txn).await {
+                        Ok(()) => rpc.send(msg.reply("txn_ok", Some(TxnData { txn }))),
+                        Err(e) => rpc.send(msg.error(e)),
+                    };
+                    if let Err(e) = sent {
+                        error!("failed to reply to {}: {:#}", msg.src, e);
+                    }
+                });
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+// commit runs `txn` against the database in `lin-kv` and then swaps in the result.
+async fn commit(lin: &Kv, txn: &mut [MicroOp]) -> Result<(), ErrorBody> {
+    let snapshot: BTreeMap<u64, Vec<u64>> = match lin.read(&ROOT).await {
+        Ok(db) => db,
+        Err(kv::Error::KeyDoesNotExist) => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let written = run(txn, |key| snapshot.get(&key).cloned().unwrap_or_default());
+    // A read-only transaction is already done, it read everything from one version.
+    if written.is_empty() {
+        return Ok(());
+    }
+
+    let mut db = snapshot.clone();
+    db.extend(written);
+    match lin.cas(&ROOT, &snapshot, &db, true).await {
+        Ok(()) => Ok(()),
+        Err(kv::Error::PreconditionFailed) => Err(ErrorBody::new(
+            ErrorCode::TxnConflict,
+            "another transaction committed first",
+        )),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Sender;
+    use crate::rpc::Rpc;
+    use std::{io::Cursor, time};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn lists() {
+        let mut store = Lists::default();
+        store.commit(BTreeMap::from([(1, vec![1]), (2, vec![5])]));
+        assert_eq!(store.read(1), vec![1]);
+        store.commit(BTreeMap::from([(1, vec![1, 2])]));
+
+        assert_eq!(store.read(1), vec![1, 2]);
+        assert_eq!(store.read(2), vec![5]);
+        assert!(store.read(3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn local() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[["r",1,null],["append",1,3],["r",1,null],["append",2,4]]}}
+{"src":"c2","dest":"n1","body":{"type":"txn","msg_id":1,"txn":[["append",1,5],["r",1,null],["r",2,null],["r",3,null]]}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"txn_ok","in_reply_to":2,"txn":[["r",1,[]],["append",1,3],["r",1,[3]],["append",2,4]]}}
+{"src":"n1","dest":"c2","body":{"type":"txn_ok","in_reply_to":1,"txn":[["append",1,5],["r",1,[3,5]],["r",2,[4]],["r",3,[]]]}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(
+            Cursor::new(input.as_bytes()),
+            &mut actual,
+            ListAppend::new(Mode::Local),
+        )
+        .await
+        .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn kv() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let service = kv::fake::serve(rpc.clone(), rx);
+        let lin = Kv::new(rpc.clone(), "n1".to_string(), kv::Service::LinKv);
+
+        let mut txn = vec![
+            MicroOp(Op::Append, 1, Some(Operand::Element(3))),
+            MicroOp(Op::Read, 1, None),
+        ];
+        commit(&lin, &mut txn).await.expect("commit failed");
+        assert_eq!(txn[1].2, Some(Operand::List(vec![3])));
+
+        let mut txn = vec![
+            MicroOp(Op::Append, 1, Some(Operand::Element(4))),
+            MicroOp(Op::Read, 2, None),
+        ];
+        commit(&lin, &mut txn).await.expect("commit failed");
+        let mut txn = vec![MicroOp(Op::Read, 1, None)];
+        commit(&lin, &mut txn).await.expect("commit failed");
+        assert_eq!(txn[0].2, Some(Operand::List(vec![3, 4])));
+
+        // Both read the same snapshot, so only the first to swap commits.
+        let (mut a, mut b) = (
+            vec![MicroOp(Op::Append, 1, Some(Operand::Element(5)))],
+            vec![MicroOp(Op::Append, 1, Some(Operand::Element(6)))],
+        );
+        let (a, b) = tokio::join!(commit(&lin, &mut a), commit(&lin, &mut b));
+        let conflicts: Vec<_> = [a, b].into_iter().filter_map(Result::err).collect();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].code, ErrorCode::TxnConflict);
+
+        service.abort();
+    }
+}
@@ -0,0 +1,382 @@
+use crate::consensus::{Protocol, Replica, RequestBody, StateMachine};
+use crate::payload::{ErrorBody, ErrorCode, Message, Payload, ResponseBody};
+use crate::{config, node, store};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+// Goals(s):
+// - Serve named locks to clients with `acquire` and `release`, so that at most one client at a
+//   time holds each lock.
+// - Hand out a fencing token with each lock, so that whatever the lock protects can turn away a
+//   client that's lost the lock but doesn't know it yet.
+// - Don't let a client that's gone away hold a lock forever.
+//
+// Maelstrom doesn't have a workload for locks, so the messages are our own:
+//
+//   {"type": "acquire", "lock": "a", "lease": 5000}
+//   {"type": "acquire_ok", "token": 3, "expires": 1757680331000}
+//   {"type": "release", "lock": "a", "token": 3}
+//   {"type": "release_ok"}
+//
+// A lock is held for a lease, in milliseconds, and is free again once it expires. The holder
+// keeps it by acquiring it again before then, which extends the lease but keeps the token. Every
+// time a lock changes hands its token goes up, so a later holder always has a higher token than
+// an earlier one. Only the holder can release a lock, and only with its current token.
+//
+// The locks are a state machine replicated like `lin_kv.rs`' registers, picked from Raft or Paxos
+// at startup, and every node forwards clients to the leader. Leases need the time, but applying a
+// command has to come out the same on every node, so the leader reads it from the node's
+// `config::TimeSource` when it proposes a command and the command carries it. The state machine's
+// clock only goes forward, so a new leader whose clock is behind the old one's can't bring an
+// expired lease back.
+
+// DEFAULT_LEASE is how long a lock is held for, in milliseconds, when `acquire` doesn't say.
+const DEFAULT_LEASE: u64 = 10_000;
+
+/// Request is what clients send.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    Acquire {
+        lock: String,
+        // In milliseconds.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<u64>,
+        // Who the lock is for, when the request was forwarded by another node. Otherwise it's
+        // whoever sent it, whatever it says here.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
+    },
+    Release {
+        lock: String,
+        token: u64,
+        // The same as for `acquire`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        owner: Option<String>,
+    },
+}
+
+impl Request {
+    fn reply_type(&self) -> &'static str {
+        match self {
+            Request::Acquire { .. } => "acquire_ok",
+            Request::Release { .. } => "release_ok",
+        }
+    }
+
+    fn owner_mut(&mut self) -> &mut Option<String> {
+        match self {
+            Request::Acquire { owner, .. } | Request::Release { owner, .. } => owner,
+        }
+    }
+}
+
+/// Command is what's replicated: a request, who it's from, and the leader's time when it was
+/// proposed, in milliseconds since the epoch.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    request: Request,
+    owner: String,
+    at: u64,
+}
+
+/// Grant is a lock that was acquired: its fencing token and when the lease runs out, in
+/// milliseconds since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    pub token: u64,
+    pub expires: u64,
+}
+
+// R is the protocol's reply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ResponseData<R> {
+    Granted(Grant),
+    Consensus(R),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Lock {
+    // Nobody holds the lock once it's released, or once `expires` has passed.
+    holder: Option<String>,
+    // The token of the latest holder, which carries on going up after the lock is released.
+    token: u64,
+    expires: u64,
+}
+
+/// Locks is the state machine: every lock that's ever been acquired.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Locks {
+    locks: HashMap<String, Lock>,
+    // The latest time any command was proposed at.
+    now: u64,
+}
+
+impl StateMachine for Locks {
+    type Command = Command;
+    // The lock that was acquired, if the command was an `acquire`.
+    type Output = Result<Option<Grant>, ErrorBody>;
+    type Snapshot = Locks;
+
+    fn apply(&mut self, command: &Command) -> Self::Output {
+        self.now = self.now.max(command.at);
+        let now = self.now;
+        match &command.request {
+            Request::Acquire {
+                lock: name, lease, ..
+            } => {
+                let lock = self.locks.entry(name.clone()).or_default();
+                // The lease is the client's to pick, so a huge one is as good as forever.
+                let expires = now.saturating_add(lease.unwrap_or(DEFAULT_LEASE));
+                match &lock.holder {
+                    // The holder renews its lease.
+                    Some(holder) if lock.expires > now && *holder == command.owner => {}
+                    Some(holder) if lock.expires > now => {
+                        return Err(ErrorBody::new(
+                            ErrorCode::PreconditionFailed,
+                            format!("lock {} is held by {} until {}", name, holder, lock.expires),
+                        ));
+                    }
+                    _ => {
+                        lock.holder = Some(command.owner.clone());
+                        lock.token += 1;
+                    }
+                }
+                lock.expires = expires;
+                Ok(Some(Grant {
+                    token: lock.token,
+                    expires,
+                }))
+            }
+            Request::Release {
+                lock: name, token, ..
+            } => {
+                match self.locks.get_mut(name) {
+                    // Tokens are easy to guess, so it has to be the holder that releases it.
+                    Some(lock)
+                        if lock.holder.as_deref() == Some(&command.owner)
+                            && lock.token == *token
+                            && lock.expires > now =>
+                    {
+                        lock.holder = None;
+                        Ok(None)
+                    }
+                    // A lease that's run out isn't the holder's to release any more, and it
+                    // should know.
+                    _ => Err(ErrorBody::new(
+                        ErrorCode::PreconditionFailed,
+                        format!("token {} doesn't hold lock {}", token, name),
+                    )),
+                }
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Locks {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: Locks) {
+        *self = snapshot;
+    }
+}
+
+/// LockService serves locks from [`Locks`] replicated with the protocol `P`.
+pub struct LockService<P: Protocol<Machine = Locks>> {
+    // Where the protocol's state is kept, if anywhere.
+    dir: Option<PathBuf>,
+    // Started on `init`, once the node knows who its peers are.
+    replica: Option<Replica<P>>,
+}
+
+impl<P: Protocol<Machine = Locks>> Default for LockService<P> {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            replica: None,
+        }
+    }
+}
+
+impl<P: Protocol<Machine = Locks>> LockService<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// with_dir keeps the protocol's state under `dir` so that the locks, and their tokens,
+    /// survive the node being restarted.
+    pub fn with_dir(mut self, dir: PathBuf) -> Self {
+        self.dir = Some(dir);
+        self
+    }
+}
+
+impl<S, T, P> node::Handler<S, T> for LockService<P>
+where
+    T: config::TimeSource,
+    S: store::Store,
+    P: Protocol<Machine = Locks>,
+{
+    type Request = RequestBody<Request, P::Request>;
+    type Response = ResponseBody<ResponseData<P::Reply>>;
+
+    fn init(&mut self, node: &mut node::Node<S, T>) -> Result<()> {
+        let dir = self.dir.as_ref().map(|dir| dir.join(&node.id));
+        let replica = Replica::start(
+            node.id.clone(),
+            node.world.keys().cloned().collect(),
+            Locks::default(),
+            dir.as_deref(),
+            node.rpc.clone(),
+        )
+        .context("failed to start replica")?;
+        self.replica = Some(replica);
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<Self::Request>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        let Some(replica) = &self.replica else {
+            anyhow::bail!("received {:?} before init", msg.body.data);
+        };
+
+        let mut request = match &msg.body.data {
+            RequestBody::Consensus(req) => {
+                let (typ, reply) = replica.handle(req);
+                return Ok(vec![msg.reply(typ, Some(ResponseData::Consensus(reply)))]);
+            }
+            RequestBody::Client(request) => request.clone(),
+        };
+        // Only another node can act on someone else's behalf, or any client could renew or release
+        // someone else's lock.
+        let from_peer = node.world.contains_key(&msg.src);
+        let owner = match request.owner_mut() {
+            Some(owner) if from_peer => owner.clone(),
+            _ => msg.src.clone(),
+        };
+        // The owner goes along with a forwarded request, or the leader would take it to be from
+        // this node.
+        *request.owner_mut() = Some(owner.clone());
+
+        let at = node
+            .config
+            .time_source
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .context("clock is before the epoch")?
+            .as_millis() as u64;
+        let typ = request.reply_type();
+        let forward = (!from_peer).then(|| request.clone());
+        let command = Command { request, owner, at };
+        replica.serve(msg, command, forward, typ, ResponseData::Granted, |reply| {
+            serde_json::from_value::<Grant>(reply).ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft::Raft;
+    use std::{io::Cursor, time};
+
+    fn acquire(lock: &str, lease: u64, owner: &str, at: u64) -> Command {
+        Command {
+            request: Request::Acquire {
+                lock: lock.to_string(),
+                lease: Some(lease),
+                owner: None,
+            },
+            owner: owner.to_string(),
+            at,
+        }
+    }
+
+    fn release(lock: &str, token: u64, owner: &str, at: u64) -> Command {
+        Command {
+            request: Request::Release {
+                lock: lock.to_string(),
+                token,
+                owner: None,
+            },
+            owner: owner.to_string(),
+            at,
+        }
+    }
+
+    #[test]
+    fn locks() {
+        let mut l = Locks::default();
+        let grant = |token, expires| Ok(Some(Grant { token, expires }));
+        assert_eq!(l.apply(&acquire("a", 100, "c1", 1000)), grant(1, 1100));
+        let e = l.apply(&acquire("a", 100, "c2", 1050)).unwrap_err();
+        assert_eq!(e.code, ErrorCode::PreconditionFailed);
+        // Other locks are separate, and the holder renews its lease with the same token.
+        assert_eq!(l.apply(&acquire("b", 100, "c2", 1050)), grant(1, 1150));
+        assert_eq!(l.apply(&acquire("a", 100, "c1", 1050)), grant(1, 1150));
+
+        // Once the lease runs out the lock changes hands, and the old token is no good.
+        assert_eq!(l.apply(&acquire("a", 100, "c2", 1150)), grant(2, 1250));
+        let e = l.apply(&release("a", 1, "c1", 1160)).unwrap_err();
+        assert_eq!(e.code, ErrorCode::PreconditionFailed);
+        // Nor can anyone but the holder release it, even with the right token.
+        let e = l.apply(&release("a", 2, "c1", 1160)).unwrap_err();
+        assert_eq!(e.code, ErrorCode::PreconditionFailed);
+        assert_eq!(l.apply(&release("a", 2, "c2", 1160)), Ok(None));
+        assert!(l.apply(&release("a", 2, "c2", 1160)).is_err());
+
+        // Time doesn't go backwards for a leader with a slow clock.
+        assert_eq!(l.apply(&acquire("a", 100, "c1", 500)), grant(3, 1260));
+
+        // A lease too long to add up is held until the end of time.
+        assert_eq!(
+            l.apply(&acquire("c", u64::MAX, "c1", 1260)),
+            grant(1, u64::MAX)
+        );
+    }
+
+    #[tokio::test]
+    async fn single_node() {
+        let input = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"acquire","msg_id":2,"lock":"a","lease":5000}}
+{"src":"c2","dest":"n1","body":{"type":"acquire","msg_id":3,"lock":"a"}}
+{"src":"c2","dest":"n1","body":{"type":"acquire","msg_id":7,"lock":"a","owner":"c1"}}
+{"src":"c1","dest":"n1","body":{"type":"release","msg_id":4,"lock":"a","token":1}}
+{"src":"c2","dest":"n1","body":{"type":"acquire","msg_id":5,"lock":"a"}}
+{"src":"c2","dest":"n1","body":{"type":"steal","msg_id":6,"lock":"a"}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"acquire_ok","in_reply_to":2,"token":1,"expires":1757680331000}}
+{"src":"n1","dest":"c2","body":{"type":"error","in_reply_to":3,"code":22,"text":"lock a is held by c1 until 1757680331000"}}
+{"src":"n1","dest":"c2","body":{"type":"error","in_reply_to":7,"code":22,"text":"lock a is held by c1 until 1757680331000"}}
+{"src":"n1","dest":"c1","body":{"type":"release_ok","in_reply_to":4}}
+{"src":"n1","dest":"c2","body":{"type":"acquire_ok","in_reply_to":5,"token":2,"expires":1757680336000}}
+{"src":"n1","dest":"c2","body":{"type":"error","in_reply_to":6,"code":10,"text":"unknown variant `steal`, expected a client or consensus request"}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(
+            Cursor::new(input.as_bytes()),
+            &mut actual,
+            LockService::<Raft<Locks>>::new(),
+        )
+        .await
+        .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+}
@@ -1,22 +1,23 @@
-use crate::payload::Payload;
-use crate::{config, store};
-use serde::Serialize;
+use crate::payload::{ErrorBody, ErrorCode, Message, Payload, RequestBody, ResponseBody};
+use crate::{config, kv, outbox, rpc, store};
+use anyhow::Context;
 use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 use tracing::{error, info};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Metadata {
     pub priority: u8,
 }
 
 pub struct Node<S: store::Store, T: config::TimeSource> {
     pub id: String, // include it as the src of any message it sends.
-    pub msg_id: u32,
     pub world: HashMap<String, Metadata>,
     pub neighborhood: HashMap<String, Metadata>,
     // Do we use a HashSet (empty values) or HashMap with a value of `seen_by`?
@@ -31,54 +32,330 @@ pub struct Node<S: store::Store, T: config::TimeSource> {
     // And it needs(?) to be a type that can be owned for calling `.lines()` on it.
     pub store: Arc<Mutex<S>>,
     pub config: config::Config<T>,
+    // Everything the node writes goes through `rpc`, which is cloneable so that spawned tasks can
+    // send and await replies while the dispatch loop keeps running.
+    pub rpc: rpc::Rpc,
+    // Node-to-node messages that need to survive partitions go through the outbox instead.
+    pub outbox: outbox::Outbox,
+    out_rx: Option<mpsc::UnboundedReceiver<Payload<Value>>>,
 }
 
 impl<S: store::Store, T: config::TimeSource> Node<S, T> {
     pub fn new(s: S, config: config::Config<T>) -> Self {
+        let (out_tx, out_rx) = mpsc::unbounded_channel::<Payload<Value>>();
+        let rpc = rpc::Rpc::new(Sender::new(out_tx));
+        let outbox = outbox::Outbox::new(
+            rpc.clone(),
+            outbox::Backoff::default(),
+            config.time_source.clone(),
+        );
         Self {
             id: std::default::Default::default(),
-            msg_id: std::default::Default::default(),
             world: std::default::Default::default(),
             neighborhood: std::default::Default::default(),
             seen: std::default::Default::default(),
             store: Arc::new(Mutex::new(s)),
             config,
+            rpc: rpc.clone(),
+            outbox,
+            out_rx: Some(out_rx),
         }
     }
 
     pub fn init(&mut self, node_id: String, node_ids: Vec<String>) {
-        self.msg_id = 1;
         self.id = node_id;
         self.neighborhood = HashMap::new();
         self.world = HashMap::new();
 
+        // NOTE: The neighborhood is left to the handler, since who a node talks to, and how
+        // peers are prioritized, is up to the workload.
         for n in node_ids {
             if n == self.id {
                 continue;
             }
+            self.world.insert(n, Metadata::default());
+        }
+    }
+
+    /// kv is a client for one of Maelstrom's key/value services that sends as this node. It's
+    /// only addressed correctly once the node has been initialized.
+    pub fn kv(&self, service: kv::Service) -> kv::Kv {
+        kv::Kv::new(self.rpc.clone(), self.id.clone(), service)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InitBody {
+    msg_id: u32,
+    node_id: String,
+    node_ids: Vec<String>,
+}
+
+/// Sender is a cloneable handle for queueing messages that will be written to stdout.
+///
+/// Bodies are serialized as they are queued so that a single writer can interleave replies from
+/// the node itself (e.g., `init_ok`) with whatever a workload sends.
+#[derive(Debug, Clone)]
+pub struct Sender(mpsc::UnboundedSender<Payload<Value>>);
+
+impl Sender {
+    pub(crate) fn new(tx: mpsc::UnboundedSender<Payload<Value>>) -> Self {
+        Self(tx)
+    }
+
+    pub fn send<B: Serialize>(&self, msg: Payload<B>) -> anyhow::Result<()> {
+        let body = serde_json::to_value(msg.body).context("failed to serialize body")?;
+        self.0
+            .send(Payload {
+                src: msg.src,
+                dest: msg.dest,
+                body,
+            })
+            .context("output channel is closed")
+    }
+}
+
+/// Handler is implemented once per workload and plugged into [`Node::run`].
+///
+/// The node takes care of reading and parsing messages, answering `init` and writing whatever a
+/// handler returns. Replies are built with [`Payload::reply`] which takes care of addressing and
+/// `in_reply_to`.
+pub trait Handler<S: store::Store, T: config::TimeSource> {
+    /// Request is the workload's `type`-tagged enum of message bodies. The `msg_id` is not part
+    /// of it, it's carried by [`RequestBody`].
+    type Request: DeserializeOwned + std::fmt::Debug;
+    type Response: Serialize;
+
+    fn handle(
+        &mut self,
+        node: &mut Node<S, T>,
+        msg: Message<Self::Request>,
+    ) -> anyhow::Result<Vec<Payload<Self::Response>>>;
+
+    /// init is called once the node knows its id and the other nodes, before `init_ok` is sent,
+    /// e.g., to recover state kept from before a crash. If it fails, `init_ok` isn't sent.
+    fn init(&mut self, _node: &mut Node<S, T>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: store::Store, T: config::TimeSource> Node<S, T> {
+    /// run is the entry point shared by every workload binary.
+    ///
+    /// It reads messages from the reader (stdin), answers `init` itself and hands every other
+    /// message to the handler, and writes whatever the handler returns to the writer (stdout). It
+    /// returns once the reader is closed --- or the process is interrupted --- and every message
+    /// that was queued before that point has been written.
+    pub async fn run<R, W, H>(&mut self, reader: R, writer: W, mut handler: H) -> anyhow::Result<()>
+    where
+        R: AsyncBufReadExt + Unpin,
+        W: AsyncWriteExt + Unpin,
+        H: Handler<S, T>,
+    {
+        let (in_tx, mut in_rx) = mpsc::unbounded_channel::<Result<Payload<Value>, Malformed>>();
+        let mut out_rx = self.out_rx.take().context("node is already running")?;
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let input = async {
+            tokio::select! {
+                res = read(reader, in_tx) => res.context("failed while reading"),
+                _ = tokio::signal::ctrl_c() => {
+                    info!("received interrupt, shutting down...");
+                    Ok(())
+                }
+            }
+        };
+
+        let dispatch = async {
+            while let Some(msg) = in_rx.recv().await {
+                let res = match msg {
+                    Ok(msg) => self.dispatch(msg, &mut handler),
+                    Err(m) => self.reply_malformed(m),
+                };
+                if let Err(e) = res {
+                    error!("failed to handle message: {:#}", e);
+                }
+            }
+            // The node (and any task it spawned) still holds a sender, so the writer has to be
+            // told that nothing else is coming.
+            let _ = shutdown_tx.send(());
+            Ok(())
+        };
+
+        let output = async {
+            write(writer, &mut out_rx, shutdown_rx)
+                .await
+                .context("failed while writing")
+        };
+
+        let res = tokio::try_join!(input, dispatch, output);
+        self.out_rx = Some(out_rx);
+        res?;
+        Ok(())
+    }
+
+    fn dispatch<H>(&mut self, msg: Payload<Value>, handler: &mut H) -> anyhow::Result<()>
+    where
+        H: Handler<S, T>,
+    {
+        let Some(msg) = self.rpc.resolve(msg) else {
+            return Ok(());
+        };
+
+        if msg.body.get("in_reply_to").is_some() {
+            // Most likely a reply that arrived after the caller timed out.
+            info!("dropping reply nobody is waiting on: {:?}", msg);
+            return Ok(());
+        }
 
-            // Let's pretend (for now, just use a random number generator)
-            // the init message included weights
-            //
-            // NOTE: We don't filter the neighborhood here because filtering where
-            // messages go will be up to the send/response implementation.
-            // In other words, there may be cases where we want to send a message
-            // to everyone and other places where we want to be selective.
-            let priority: u8 = rand::random_range(0..=100);
-            self.world.insert(n.clone(), Metadata { priority });
-
-            if priority > 33 {
-                self.neighborhood.insert(n.clone(), Metadata { priority });
+        if msg.body.get("type").and_then(Value::as_str) == Some("init") {
+            let body: InitBody =
+                serde_json::from_value(msg.body).context("failed to deserialize init")?;
+            self.init(body.node_id, body.node_ids);
+            handler.init(self).context("failed to initialize handler")?;
+
+            return self.rpc.send(Payload {
+                src: self.id.clone(),
+                dest: msg.src,
+                body: ResponseBody::<()> {
+                    typ: "init_ok".to_string(),
+                    in_reply_to: body.msg_id,
+                    data: None,
+                },
+            });
+        }
+
+        let msg_id = msg.body.get("msg_id").and_then(Value::as_u64);
+        let body: RequestBody<H::Request> = match (serde_json::from_value(msg.body), msg_id) {
+            (Ok(body), _) => body,
+            // Without a `msg_id` there is nothing the sender could match an error against.
+            (Err(e), None) => return Err(e).context("failed to deserialize body"),
+            (Err(e), Some(msg_id)) => {
+                let code = if is_unknown_type(&e) {
+                    ErrorCode::NotSupported
+                } else {
+                    ErrorCode::MalformedRequest
+                };
+                let reply_to = Payload {
+                    src: msg.src,
+                    dest: msg.dest,
+                    body: RequestBody {
+                        msg_id: msg_id as u32,
+                        data: (),
+                    },
+                };
+                return self
+                    .rpc
+                    .send(reply_to.error(ErrorBody::new(code, e.to_string())));
             }
+        };
+        let msg = Payload {
+            src: msg.src,
+            dest: msg.dest,
+            body,
+        };
+        let reply_to = Payload {
+            src: msg.src.clone(),
+            dest: msg.dest.clone(),
+            body: RequestBody {
+                msg_id: msg.body.msg_id,
+                data: (),
+            },
+        };
+
+        match handler.handle(self, msg) {
+            Ok(out) => {
+                for m in out {
+                    self.rpc.send(m)?;
+                }
+                Ok(())
+            }
+            // A handler that fails with an `ErrorBody` wants the client to know about it.
+            Err(e) => match e.downcast::<ErrorBody>() {
+                Ok(body) => self.rpc.send(reply_to.error(body)),
+                Err(e) => Err(e),
+            },
         }
     }
+
+    fn reply_malformed(&mut self, m: Malformed) -> anyhow::Result<()> {
+        let reply_to = Payload {
+            src: m.src,
+            // The line may not have made it as far as `dest`.
+            dest: self.id.clone(),
+            body: RequestBody {
+                msg_id: m.msg_id,
+                data: (),
+            },
+        };
+        self.rpc
+            .send(reply_to.error(ErrorBody::new(ErrorCode::MalformedRequest, m.error)))
+    }
+}
+
+// serde reports a `type` tag that doesn't match any of the variants of an internally tagged enum
+// as an unknown variant. Anything else wrong with the body is on the sender.
+pub(crate) fn is_unknown_type(e: &serde_json::Error) -> bool {
+    e.is_data() && e.to_string().starts_with("unknown variant")
+}
+
+/// Malformed is a line that couldn't be deserialized but whose sender --- and the `msg_id` it
+/// is waiting on --- could still be made out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Malformed {
+    pub src: String,
+    pub msg_id: u32,
+    pub error: String,
+}
+
+impl Malformed {
+    /// salvage picks `src` and `body.msg_id` out of a line that isn't a valid payload.
+    ///
+    /// The line is first read as any JSON value, e.g., a payload that's missing `dest`. If it
+    /// isn't JSON at all, e.g., because it was cut short, the fields are looked for verbatim.
+    fn salvage(line: &str, error: String) -> Option<Self> {
+        let (src, msg_id) = match serde_json::from_str::<Value>(line) {
+            Ok(v) => (
+                v.get("src")?.as_str()?.to_string(),
+                v.get("body")?.get("msg_id")?.as_u64()?,
+            ),
+            Err(_) => (
+                serde_json::from_str(scan(line, "src")?).ok()?,
+                scan(line, "msg_id")?.parse().ok()?,
+            ),
+        };
+        Some(Self {
+            src,
+            msg_id: msg_id.try_into().ok()?,
+            error,
+        })
+    }
+}
+
+// scan returns the raw JSON scalar (a string, quotes included, or a number) following `"key":`.
+fn scan<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = line[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if let Some(s) = rest.strip_prefix('"') {
+        s.find('"')? + 2
+    } else {
+        rest.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len())
+    };
+    Some(&rest[..end]).filter(|v| !v.is_empty())
 }
 
 /// read reads lines from the reader and puts those lines on the tx channel.
 ///
+/// Lines that can't be deserialized are put on the channel as [`Malformed`] if there's someone to
+/// tell about it, and are otherwise logged and dropped.
+///
 /// The reader will generally be stdin per the spec of maelstrom and is not closed until the
 /// maelstrom test is finished and stdin is closed.
-pub async fn read<R, T>(src: R, tx: mpsc::UnboundedSender<Payload<T>>) -> std::io::Result<()>
+pub async fn read<R, T>(
+    src: R,
+    tx: mpsc::UnboundedSender<Result<Payload<T>, Malformed>>,
+) -> std::io::Result<()>
 where
     // Why `Unpin`?
     // it's because im creating a Future inside of a Future
@@ -94,92 +371,203 @@ where
 
     while let Some(line) = lines.next_line().await? {
         info!(">> input: {:?}", line);
-        match serde_json::from_str::<Payload<T>>(&line) {
-            Ok(msg) => {
-                if let Err(e) = tx.send(msg) {
-                    error!("failed while gossiping message: {}", e);
+        let msg = match serde_json::from_str::<Payload<T>>(&line) {
+            Ok(msg) => Ok(msg),
+            Err(e) => match Malformed::salvage(&line, e.to_string()) {
+                Some(m) => Err(m),
+                None => {
+                    error!("failed to deserialize payload: {}", e);
+                    continue;
                 }
-            }
-            Err(e) => error!("failed to deserialize payload: {}", e),
+            },
         };
+        if let Err(e) = tx.send(msg) {
+            error!("failed while gossiping message: {}", e);
+        }
     }
     Ok(())
 }
 
 /// write receives messages from the rx channel, serializes them to JSON and writes them to the
 /// writer --- which is stdout per the maelstrom spec.
+///
+/// Once `shutdown` fires, whatever is already queued is written and then write returns.
 pub async fn write<W, T>(
     mut w: W,
-    mut rx: mpsc::UnboundedReceiver<Payload<T>>,
+    rx: &mut mpsc::UnboundedReceiver<Payload<T>>,
+    mut shutdown: oneshot::Receiver<()>,
 ) -> std::io::Result<()>
 where
     W: AsyncWriteExt + Unpin,
     T: Serialize + Send,
 {
-    while let Some(m) = rx.recv().await {
-        let mut o = serde_json::to_vec(&m)?;
-        o.push(b'\n');
-        w.write_all(&o).await?;
+    loop {
+        let m = tokio::select! {
+            biased;
+            m = rx.recv() => match m {
+                Some(m) => m,
+                None => break,
+            },
+            _ = &mut shutdown => {
+                while let Ok(m) = rx.try_recv() {
+                    write_line(&mut w, &m).await?;
+                }
+                break;
+            }
+        };
+        write_line(&mut w, &m).await?;
     }
     Ok(())
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::{config, echo, node, store};
-//     use once_cell::sync::Lazy;
-//     use std::io::Cursor;
-//     use std::time;
-//     use std::vec::Vec;
-//
-//     // Ensure that the `tracing` stack is only initialised once using `once_cell`
-//     pub static TRACING: Lazy<()> = Lazy::new(|| {
-//         tracing_subscriber::fmt()
-//             .with_writer(std::io::stderr) // all debug logs have to go to stderr
-//             .with_max_level(tracing::Level::DEBUG)
-//             .init();
-//     });
-//
-//     #[test]
-//     fn run() {
-//         // The first time `initialize` is invoked the code in `TRACING` is executed.
-//         // All other invocations will instead skip execution.
-//         Lazy::force(&TRACING);
-//
-//         let test_cases = vec![
-//             (
-//                 r#"{"id":42,"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"32","node_ids":["n1","n2","n3"]}}
-// "#,
-//                 r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
-// "#,
-//             ),
-//             (
-//                 r#"{"src":"f11","dest":"z10","body":{"type":"echo","msg_id":99,"echo":"Please echo 99"}}
-// "#,
-//                 r#"{"src":"z10","dest":"f11","body":{"type":"echo_ok","in_reply_to":99,"echo":"Please echo 99"}}
-// "#,
-//             ),
-//         ];
-//
-//         for (input, expected) in test_cases {
-//             let buf: Vec<u8> = Vec::new();
-//             let mut s = store::MemoryStore::new(buf).expect("failed to create store");
-//             let cfg = config::Config::<config::MockTime>::new(config::MockTime {
-//                 now: time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1757680326),
-//             })
-//             .expect("failed to get config");
-//             let mut n: node::Node<store::MemoryStore, config::MockTime> =
-//                 node::Node::new(&mut s, cfg);
-//
-//             // Necessary to implement Read trait on BufReader for bytes
-//             let mut vec: Vec<u8> = Vec::new();
-//             let write_cursor = Cursor::new(&mut vec);
-//             let read_cursor = Cursor::new(input.as_bytes());
-//
-//             n.run(read_cursor, write_cursor, echo::listen)
-//                 .expect("Node did NOT run");
-//
-//             assert_eq!(String::from_utf8(vec).unwrap().trim(), expected.trim());
-//         }
-//     }
-// }
+async fn write_line<W, T>(w: &mut W, m: &Payload<T>) -> std::io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+    T: Serialize,
+{
+    let mut o = serde_json::to_vec(m)?;
+    o.push(b'\n');
+    info!("<< output: {:?}", String::from_utf8_lossy(&o));
+    w.write_all(&o).await?;
+    // Maelstrom reads line-by-line so we can't leave a reply sitting in a buffer.
+    w.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use std::io::Cursor;
+    use std::time;
+
+    // Ensure that the `tracing` stack is only initialised once using `once_cell`
+    pub static TRACING: Lazy<()> = Lazy::new(|| {
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr) // all debug logs have to go to stderr
+            .with_max_level(tracing::Level::DEBUG)
+            .init();
+    });
+
+    #[derive(Debug, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "lowercase")]
+    enum EchoRequest {
+        Echo { echo: String },
+    }
+
+    #[derive(Debug, Serialize)]
+    struct EchoData {
+        echo: String,
+    }
+
+    struct Echo;
+
+    impl Handler<store::MemoryStore, config::MockTime> for Echo {
+        type Request = EchoRequest;
+        type Response = ResponseBody<EchoData>;
+
+        fn handle(
+            &mut self,
+            _node: &mut Node<store::MemoryStore, config::MockTime>,
+            msg: Message<EchoRequest>,
+        ) -> anyhow::Result<Vec<Payload<Self::Response>>> {
+            match &msg.body.data {
+                EchoRequest::Echo { echo } => Ok(vec![
+                    msg.reply("echo_ok", Some(EchoData { echo: echo.clone() })),
+                ]),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn run() {
+        // The first time `initialize` is invoked the code in `TRACING` is executed.
+        // All other invocations will instead skip execution.
+        Lazy::force(&TRACING);
+
+        let input = r#"{"id":42,"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}
+{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"Please echo 2"}}
+not json
+{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":3,"echo":"Please echo 3"}}
+{"src":"c1","dest":"n1","body":{"type":"shout","msg_id":4}}
+{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":5}}
+{"src":"c1","body":{"type":"echo","msg_id":6,"echo":"no dest"}}
+{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":7,"ec
+{"src":"c1","dest":"n1","body":{"type":"echo","echo":"no msg_id"}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"echo_ok","in_reply_to":2,"echo":"Please echo 2"}}
+{"src":"n1","dest":"c1","body":{"type":"echo_ok","in_reply_to":3,"echo":"Please echo 3"}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":4,"code":10,"text":"unknown variant `shout`, expected `echo`"}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":5,"code":12,"text":"missing field `echo`"}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":6,"code":12,"text":"missing field `dest` at line 1 column 63"}}
+{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":7,"code":12,"text":"EOF while parsing a string at line 1 column 60"}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: Node<store::MemoryStore, config::MockTime> = Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(Cursor::new(input.as_bytes()), &mut actual, Echo)
+            .await
+            .expect("Node did NOT run");
+
+        assert_eq!(n.id, "n1");
+        assert_eq!(n.world.len(), 2);
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "lowercase")]
+    enum PingRequest {
+        Ping,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Pong {
+        in_reply_to: u32,
+    }
+
+    #[tokio::test]
+    async fn rpc_reply() {
+        Lazy::force(&TRACING);
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::SystemTime::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: Node<store::MemoryStore, config::MockTime> = Node::new(s, cfg);
+
+        let pong = n.rpc.call::<_, Pong>(
+            Payload {
+                src: "n1".to_string(),
+                dest: "n2".to_string(),
+                body: PingRequest::Ping,
+            },
+            time::Duration::from_secs(5),
+        );
+
+        // Replies never reach the handler, whether or not someone is waiting on them.
+        let input = r#"{"src":"n2","dest":"n1","body":{"type":"pong","in_reply_to":1}}
+{"src":"n2","dest":"n1","body":{"type":"pong","in_reply_to":7}}
+"#;
+        let expected = r#"{"src":"n1","dest":"n2","body":{"msg_id":1,"type":"ping"}}
+"#;
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(Cursor::new(input.as_bytes()), &mut actual, Echo)
+            .await
+            .expect("Node did NOT run");
+
+        let pong = pong.await.expect("expected a reply");
+        assert_eq!(pong.src, "n2");
+        assert_eq!(pong.body.in_reply_to, 1);
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+}
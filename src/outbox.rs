@@ -0,0 +1,296 @@
+use crate::config::TimeSource;
+use crate::payload::{ErrorCode, Payload};
+use crate::rpc::{self, Rpc};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+// Maelstrom's partition nemesis drops messages between nodes without telling anyone. The only
+// way to know a peer got something is for it to acknowledge it, so node-to-node messages that
+// matter sit in the outbox until they're acked and are re-sent in the meantime.
+//
+// Re-sends back off exponentially so that a partitioned peer isn't flooded, with jitter so that
+// every node doesn't retry in lock-step once the partition heals. A message is given up on once
+// it has expired, the same way a broadcast message stops being gossiped once it has expired.
+// Expiry goes by the node's `TimeSource`, the same clock that expirations are set from.
+
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+            multiplier: 2,
+        }
+    }
+}
+
+impl Backoff {
+    /// delay is how long to wait for an ack to the given (zero-indexed) attempt before
+    /// re-sending. It's somewhere between half and all of the exponential delay.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .multiplier
+            .checked_pow(attempt)
+            .and_then(|m| self.initial.checked_mul(m))
+            .map_or(self.max, |d| d.min(self.max));
+        exp.mul_f64(rand::rng().random_range(0.5..=1.0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Unacked {
+    pub dest: String,
+    pub attempts: u32,
+    pub expiration: SystemTime,
+}
+
+/// Outbox is a cloneable handle for sending node-to-node messages that are retried until they
+/// are acknowledged or expire.
+#[derive(Clone)]
+pub struct Outbox {
+    rpc: Rpc,
+    backoff: Backoff,
+    time_source: Arc<dyn TimeSource>,
+    next: Arc<AtomicU64>,
+    unacked: Arc<Mutex<HashMap<u64, Unacked>>>,
+}
+
+impl Outbox {
+    pub fn new(rpc: Rpc, backoff: Backoff, time_source: Arc<dyn TimeSource>) -> Self {
+        Self {
+            rpc,
+            backoff,
+            time_source,
+            next: Default::default(),
+            unacked: Default::default(),
+        }
+    }
+
+    /// send keeps sending `msg` in the background until any reply to it arrives or
+    /// `expiration` passes.
+    ///
+    /// The returned handle resolves to whether the message was acknowledged.
+    pub fn send<B>(&self, msg: Payload<B>, expiration: SystemTime) -> tokio::task::JoinHandle<bool>
+    where
+        B: Serialize + Clone + Send + 'static,
+    {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        self.unacked
+            .lock()
+            .expect("failed to take outbox lock")
+            .insert(
+                id,
+                Unacked {
+                    dest: msg.dest.clone(),
+                    attempts: 0,
+                    expiration,
+                },
+            );
+
+        let outbox = self.clone();
+        tokio::spawn(async move {
+            let acked = outbox.deliver(id, msg, expiration).await;
+            outbox
+                .unacked
+                .lock()
+                .expect("failed to take outbox lock")
+                .remove(&id);
+            acked
+        })
+    }
+
+    async fn deliver<B>(&self, id: u64, msg: Payload<B>, expiration: SystemTime) -> bool
+    where
+        B: Serialize + Clone,
+    {
+        let mut attempt = 0;
+        while self.time_source.now() < expiration {
+            let wait = self.backoff.delay(attempt);
+            if let Some(u) = self
+                .unacked
+                .lock()
+                .expect("failed to take outbox lock")
+                .get_mut(&id)
+            {
+                u.attempts = attempt + 1;
+            }
+
+            // Every attempt gets a new msg_id, so a late ack for an earlier attempt is simply
+            // dropped by the node.
+            match self.rpc.call::<B, Value>(msg.clone(), wait).await {
+                Ok(_) => return true,
+                Err(rpc::Error::Timeout) => {
+                    debug!("no ack from {} after attempt {}", msg.dest, attempt + 1);
+                }
+                // The peer got it but can't take it right now.
+                Err(rpc::Error::Reply(e))
+                    if e.code == ErrorCode::TemporarilyUnavailable || !e.code.definite() =>
+                {
+                    debug!(
+                        "{} is unavailable after attempt {}: {}",
+                        msg.dest,
+                        attempt + 1,
+                        e
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                Err(e) => {
+                    warn!("giving up on message to {}: {}", msg.dest, e);
+                    return false;
+                }
+            }
+            attempt += 1;
+        }
+
+        info!(
+            "message to {} expired after {} attempts without an ack",
+            msg.dest, attempt
+        );
+        false
+    }
+
+    /// len is the number of messages that haven't been acknowledged yet.
+    pub fn len(&self) -> usize {
+        self.unacked
+            .lock()
+            .expect("failed to take outbox lock")
+            .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// unacked is a snapshot of the messages that haven't been acknowledged yet.
+    pub fn unacked(&self) -> Vec<Unacked> {
+        self.unacked
+            .lock()
+            .expect("failed to take outbox lock")
+            .values()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::node::Sender;
+    use tokio::sync::mpsc;
+
+    #[derive(Debug, Clone, Serialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "lowercase")]
+    enum Request {
+        Gossip,
+    }
+
+    fn gossip() -> Payload<Request> {
+        Payload {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: Request::Gossip,
+        }
+    }
+
+    fn backoff() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(40),
+            multiplier: 2,
+        }
+    }
+
+    #[test]
+    fn delay() {
+        let b = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            multiplier: 2,
+        };
+        for (attempt, exp) in [(0, 100), (1, 200), (3, 800), (4, 1000), (40, 1000)] {
+            let d = b.delay(attempt);
+            assert!(d <= Duration::from_millis(exp), "attempt {attempt}: {d:?}");
+            assert!(
+                d >= Duration::from_millis(exp / 2),
+                "attempt {attempt}: {d:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_until_acked() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let outbox = Outbox::new(rpc.clone(), backoff(), Arc::new(config::SystemTime));
+
+        let handle = outbox.send(gossip(), SystemTime::now() + Duration::from_secs(10));
+        assert_eq!(outbox.len(), 1);
+
+        // Drop the first attempt on the floor, ack the second.
+        let first = rx.recv().await.expect("expected a first attempt");
+        let second = rx.recv().await.expect("expected a retry");
+        assert_eq!(first.dest, "n2");
+        assert_eq!(first.body["type"], "gossip");
+        assert_ne!(first.body["msg_id"], second.body["msg_id"]);
+
+        let ack = Payload {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: serde_json::json!({"type": "gossip_ok", "in_reply_to": second.body["msg_id"]}),
+        };
+        assert!(rpc.resolve(ack).is_none());
+
+        assert!(handle.await.expect("delivery task failed"));
+        assert!(outbox.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expires() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let outbox = Outbox::new(
+            Rpc::new(Sender::new(tx)),
+            backoff(),
+            Arc::new(config::SystemTime),
+        );
+
+        let handle = outbox.send(gossip(), SystemTime::now() + Duration::from_millis(50));
+        assert!(!handle.await.expect("delivery task failed"));
+        assert!(outbox.is_empty());
+
+        let mut attempts = 0;
+        while rx.try_recv().is_ok() {
+            attempts += 1;
+        }
+        assert!(attempts > 1, "expected retries, got {attempts} attempt(s)");
+    }
+
+    #[tokio::test]
+    async fn expires_by_time_source() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1757680326);
+        let outbox = Outbox::new(
+            Rpc::new(Sender::new(tx)),
+            backoff(),
+            Arc::new(config::MockTime { now }),
+        );
+
+        // It's already expired by the node's clock, whatever the system clock says.
+        let handle = outbox.send(gossip(), now - Duration::from_secs(1));
+        assert!(!handle.await.expect("delivery task failed"));
+        assert!(rx.try_recv().is_err());
+    }
+}
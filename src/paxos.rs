@@ -0,0 +1,796 @@
+use crate::consensus::{Applied, NotLeader, Outgoing, Protocol, Resolver, StateMachine};
+use crate::payload::{ErrorBody, ErrorCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+// Multi-Paxos, as described in "Paxos Made Simple":
+// https://lamport.azurewebsites.net/pubs/paxos-simple.pdf
+//
+// Commands are chosen for numbered slots, and every node applies the chosen commands to its state
+// machine in slot order. Each slot is decided by a round of Paxos, but a leader only has to run the
+// first phase once for all of them: it picks a ballot higher than any it's seen and sends
+// `prepare`, and once a majority has promised to ignore lower ballots it's the leader. The
+// promises carry whatever each node has accepted from the first slot the leader doesn't know to
+// be chosen on, and for each of those slots the leader proposes the value accepted with the
+// highest ballot, or a no-op for slots where nothing was, so that nothing that might have been
+// chosen is lost.
+//
+// After that the leader only runs the second phase, with `accept`. A value is chosen once a
+// majority has accepted it, and the leader tells everyone else in the next `accept` it sends them.
+// Proposals are sent in batches on the tick rather than one message each, and an `accept` doubles
+// as the heartbeat that keeps followers from preparing ballots of their own.
+//
+// Unlike `raft.rs` nothing is written to disk, so a node that crashes has to be treated as gone
+// for good. Neither is the log ever truncated, since a node that's fallen behind is caught up from
+// it.
+//
+// `Paxos` is a `consensus::Protocol`, so it's run on the node's RPC layer by `consensus::Replica`.
+
+// ELECTION_TIMEOUT is the least time a follower waits to hear from a leader before preparing a
+// ballot of its own. Each wait is randomized up to twice that so that candidates don't keep
+// preempting each other.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+// HEARTBEAT_INTERVAL is how often the leader sends `accept` even when there's nothing new.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+// MAX_SLOTS caps how many proposals, and how many chosen values, a single `accept` carries.
+const MAX_SLOTS: usize = 100;
+
+/// Ballot orders the attempts at leading. Ties between rounds are broken by node, so no two nodes
+/// ever have the same ballot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: String,
+}
+
+/// Slot is the command accepted, or chosen, for a slot, with the ballot it was proposed in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Slot<C> {
+    pub slot: u64,
+    pub ballot: Ballot,
+    // `None` is a no-op, for slots a new leader has to fill.
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proposal<C> {
+    slot: u64,
+    command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrepareData {
+    ballot: Ballot,
+    // The first slot the candidate doesn't know to be chosen.
+    from_slot: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromiseReply<C> {
+    // The highest ballot the node has promised, which is the candidate's if it's `ok`.
+    ballot: Ballot,
+    ok: bool,
+    // What the node has accepted, or knows to be chosen, from `from_slot` on.
+    accepted: Vec<Slot<C>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptData<C> {
+    ballot: Ballot,
+    proposals: Vec<Proposal<C>>,
+    // Values that were chosen, for the node to learn.
+    chosen: Vec<Slot<C>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptedReply {
+    ballot: Ballot,
+    ok: bool,
+    // The slots whose proposals were accepted.
+    slots: Vec<u64>,
+    // Every slot up to this one is known to the node to be chosen.
+    learned: u64,
+}
+
+/// Request is a message one Paxos node sends another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Request<C> {
+    Prepare(PrepareData),
+    Accept(AcceptData<C>),
+}
+
+impl<C> Request<C> {
+    /// reply_type is the type of the reply to the request.
+    pub fn reply_type(&self) -> &'static str {
+        match self {
+            Request::Prepare(_) => "prepare_ok",
+            Request::Accept(_) => "accept_ok",
+        }
+    }
+}
+
+// Replies are told apart by their fields: only promises have `accepted`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Reply<C> {
+    Promise(PromiseReply<C>),
+    Accepted(AcceptedReply),
+}
+
+impl<C> Reply<C> {
+    fn ballot(&self) -> &Ballot {
+        match self {
+            Reply::Promise(r) => &r.ballot,
+            Reply::Accepted(r) => &r.ballot,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Role<C> {
+    Follower,
+    Candidate {
+        promises: HashSet<String>,
+        // The value accepted with the highest ballot for each slot, from the promises so far.
+        recovered: BTreeMap<u64, Slot<C>>,
+    },
+    Leader {
+        next_slot: u64,
+        // Proposals that haven't been chosen yet, with who's accepted them besides this node.
+        proposals: BTreeMap<u64, (Option<C>, HashSet<String>)>,
+        // Every slot up to this one is known by each peer to be chosen.
+        learned: HashMap<String, u64>,
+        // Whether there are proposals that haven't been sent yet.
+        fresh: bool,
+    },
+}
+
+/// Paxos is one node's part of the algorithm, with no I/O of its own. Each node is a proposer, an
+/// acceptor and a learner.
+pub struct Paxos<M: StateMachine> {
+    id: String,
+    peers: Vec<String>,
+    // The highest ballot this node has promised, or prepared itself.
+    promised: Ballot,
+    // What this node has accepted for slots it doesn't know to be chosen yet.
+    accepted: BTreeMap<u64, Slot<M::Command>>,
+    chosen: BTreeMap<u64, Slot<M::Command>>,
+    // Every slot up to `learned` is known to be chosen, and up to `last_applied` is applied.
+    learned: u64,
+    last_applied: u64,
+    role: Role<M::Command>,
+    leader: Option<String>,
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    machine: M,
+    // Proposals waiting to be applied by slot, with the ballot they were proposed in.
+    pending: HashMap<u64, (Ballot, Resolver<M::Output>)>,
+}
+
+impl<M: StateMachine> Paxos<M> {
+    /// new starts a follower. A node without peers is its own majority, so it leads right away.
+    pub fn new(id: String, peers: Vec<String>, machine: M, now: Instant) -> Self {
+        let mut paxos = Self {
+            id,
+            peers,
+            promised: Ballot::default(),
+            accepted: BTreeMap::new(),
+            chosen: BTreeMap::new(),
+            learned: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now,
+            next_heartbeat: now,
+            machine,
+            pending: HashMap::new(),
+        };
+        paxos.reset_election(now);
+        if paxos.peers.is_empty() {
+            paxos.prepare(now);
+        }
+        paxos
+    }
+
+    pub fn machine(&self) -> &M {
+        &self.machine
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.promised
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn reset_election(&mut self, now: Instant) {
+        let wait = ELECTION_TIMEOUT.mul_f64(rand::rng().random_range(1.0..2.0));
+        self.election_deadline = now + wait;
+    }
+
+    // observe promises `ballot` if it's higher than any so far, which ends whatever attempt at
+    // leading this node was making.
+    fn observe(&mut self, ballot: &Ballot) {
+        if *ballot > self.promised {
+            debug!("stepping down for ballot {:?}", ballot);
+            self.promised = ballot.clone();
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+    }
+
+    // known is what this node has accepted or knows to be chosen from `from` on. A chosen value
+    // is reported with the ballot it was chosen in, which any later proposal for it agrees with.
+    fn known(&self, from: u64) -> impl Iterator<Item = &Slot<M::Command>> {
+        let accepted = self
+            .accepted
+            .range(from..)
+            .filter(|(slot, _)| !self.chosen.contains_key(slot));
+        self.chosen
+            .range(from..)
+            .chain(accepted)
+            .map(|(_, slot)| slot)
+    }
+
+    fn prepare(&mut self, now: Instant) -> Outgoing<Request<M::Command>> {
+        let ballot = Ballot {
+            round: self.promised.round + 1,
+            node: self.id.clone(),
+        };
+        self.promised = ballot.clone();
+        self.leader = None;
+        let from_slot = self.learned + 1;
+        let mut recovered = BTreeMap::new();
+        for slot in self.known(from_slot) {
+            recovered.insert(slot.slot, slot.clone());
+        }
+        self.role = Role::Candidate {
+            promises: HashSet::from([self.id.clone()]),
+            recovered,
+        };
+        self.reset_election(now);
+        info!("preparing ballot {:?}", ballot);
+
+        if self.quorum() == 1 {
+            return self.become_leader(now);
+        }
+        let prepare = PrepareData { ballot, from_slot };
+        self.peers
+            .iter()
+            .map(|p| (p.clone(), Request::Prepare(prepare.clone())))
+            .collect()
+    }
+
+    // become_leader proposes again whatever might have been chosen in the slots it doesn't know
+    // to be, and no-ops for the gaps between them.
+    fn become_leader(&mut self, now: Instant) -> Outgoing<Request<M::Command>> {
+        let Role::Candidate { recovered, .. } = std::mem::replace(&mut self.role, Role::Follower)
+        else {
+            return vec![];
+        };
+        info!("leading with ballot {:?}", self.promised);
+        let last = recovered
+            .keys()
+            .chain(self.chosen.keys())
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(self.learned);
+        self.role = Role::Leader {
+            next_slot: last + 1,
+            proposals: BTreeMap::new(),
+            learned: self.peers.iter().map(|p| (p.clone(), 0)).collect(),
+            fresh: false,
+        };
+        self.leader = Some(self.id.clone());
+        for slot in self.learned + 1..=last {
+            if self.chosen.contains_key(&slot) {
+                continue;
+            }
+            let command = recovered.get(&slot).and_then(|s| s.command.clone());
+            self.propose_slot(slot, command);
+        }
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+        self.accepts()
+    }
+
+    // propose_slot proposes `command` for `slot` in this node's ballot, which it accepts itself.
+    fn propose_slot(&mut self, slot: u64, command: Option<M::Command>) {
+        let Role::Leader {
+            proposals, fresh, ..
+        } = &mut self.role
+        else {
+            return;
+        };
+        proposals.insert(slot, (command.clone(), HashSet::new()));
+        *fresh = true;
+        self.accepted.insert(
+            slot,
+            Slot {
+                slot,
+                ballot: self.promised.clone(),
+                command,
+            },
+        );
+        // Without peers there's nobody to wait on.
+        self.tally(slot);
+    }
+
+    // tally chooses the proposal for `slot` if a majority has accepted it.
+    fn tally(&mut self, slot: u64) {
+        let quorum = self.quorum();
+        let Role::Leader { proposals, .. } = &mut self.role else {
+            return;
+        };
+        let Some((_, voters)) = proposals.get(&slot) else {
+            return;
+        };
+        if voters.len() + 1 < quorum {
+            return;
+        }
+        let (command, _) = proposals.remove(&slot).expect("proposal");
+        self.learn(Slot {
+            slot,
+            ballot: self.promised.clone(),
+            command,
+        });
+    }
+
+    // learn records that `slot` was chosen, and applies whatever that makes possible.
+    fn learn(&mut self, slot: Slot<M::Command>) {
+        if slot.slot <= self.learned || self.chosen.contains_key(&slot.slot) {
+            return;
+        }
+        self.accepted.remove(&slot.slot);
+        self.chosen.insert(slot.slot, slot);
+        while self.chosen.contains_key(&(self.learned + 1)) {
+            self.learned += 1;
+        }
+        self.apply();
+    }
+
+    // accepts sends every peer the proposals it hasn't accepted and the chosen values it's
+    // missing, which is also the heartbeat.
+    fn accepts(&mut self) -> Outgoing<Request<M::Command>> {
+        let Role::Leader {
+            proposals,
+            learned,
+            fresh,
+            ..
+        } = &mut self.role
+        else {
+            return vec![];
+        };
+        *fresh = false;
+        self.peers
+            .iter()
+            .map(|p| {
+                let proposals = proposals
+                    .iter()
+                    .filter(|(_, (_, voters))| !voters.contains(p))
+                    .take(MAX_SLOTS)
+                    .map(|(&slot, (command, _))| Proposal {
+                        slot,
+                        command: command.clone(),
+                    })
+                    .collect();
+                let chosen = self
+                    .chosen
+                    .range(learned[p] + 1..)
+                    .take(MAX_SLOTS)
+                    .map(|(_, slot)| slot.clone())
+                    .collect();
+                let accept = AcceptData {
+                    ballot: self.promised.clone(),
+                    proposals,
+                    chosen,
+                };
+                (p.clone(), Request::Accept(accept))
+            })
+            .collect()
+    }
+
+    fn on_prepare(&mut self, p: &PrepareData, now: Instant) -> PromiseReply<M::Command> {
+        if p.ballot < self.promised {
+            return PromiseReply {
+                ballot: self.promised.clone(),
+                ok: false,
+                accepted: vec![],
+            };
+        }
+        self.observe(&p.ballot);
+        debug!("promising ballot {:?}", p.ballot);
+        self.reset_election(now);
+        PromiseReply {
+            ballot: self.promised.clone(),
+            ok: true,
+            accepted: self.known(p.from_slot).cloned().collect(),
+        }
+    }
+
+    fn on_accept(&mut self, a: &AcceptData<M::Command>, now: Instant) -> AcceptedReply {
+        if a.ballot < self.promised {
+            return AcceptedReply {
+                ballot: self.promised.clone(),
+                ok: false,
+                slots: vec![],
+                learned: self.learned,
+            };
+        }
+        self.observe(&a.ballot);
+        self.leader = Some(a.ballot.node.clone());
+        self.reset_election(now);
+
+        // A slot that's already chosen can only be proposed again with the same value.
+        for p in &a.proposals {
+            if !self.chosen.contains_key(&p.slot) && p.slot > self.learned {
+                self.accepted.insert(
+                    p.slot,
+                    Slot {
+                        slot: p.slot,
+                        ballot: a.ballot.clone(),
+                        command: p.command.clone(),
+                    },
+                );
+            }
+        }
+        for slot in &a.chosen {
+            self.learn(slot.clone());
+        }
+        AcceptedReply {
+            ballot: self.promised.clone(),
+            ok: true,
+            slots: a.proposals.iter().map(|p| p.slot).collect(),
+            learned: self.learned,
+        }
+    }
+
+    // apply runs every chosen command that hasn't been yet through the state machine, in slot
+    // order.
+    fn apply(&mut self) {
+        while self.last_applied < self.learned {
+            self.last_applied += 1;
+            let slot = &self.chosen[&self.last_applied];
+            let output = slot.command.as_ref().map(|c| self.machine.apply(c));
+
+            let Some((ballot, tx)) = self.pending.remove(&self.last_applied) else {
+                continue;
+            };
+            let res = match output {
+                Some(output) if ballot == slot.ballot => Ok(output),
+                // A later leader may have proposed the command again for the slot, or something
+                // else, and which can't be told.
+                _ => Err(ErrorBody::new(
+                    ErrorCode::Crash,
+                    "leadership was lost, the command may or may not have been chosen",
+                )),
+            };
+            let _ = tx.send(res);
+        }
+    }
+}
+
+impl<M: StateMachine> Protocol for Paxos<M> {
+    type Machine = M;
+    type Request = Request<M::Command>;
+    type Reply = Reply<M::Command>;
+
+    /// open starts a node in memory; Paxos doesn't keep anything in `dir`.
+    fn open(
+        id: String,
+        peers: Vec<String>,
+        machine: M,
+        dir: Option<&Path>,
+        now: Instant,
+    ) -> io::Result<Self> {
+        if let Some(dir) = dir {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("paxos can't keep its state in {:?}, only in memory", dir),
+            ));
+        }
+        Ok(Paxos::new(id, peers, machine, now))
+    }
+
+    /// tick does whatever is due by `now`: a leader's proposals and heartbeat, or a follower's
+    /// election.
+    fn tick(&mut self, now: Instant) -> Outgoing<Request<M::Command>> {
+        if let Role::Leader { fresh, .. } = &self.role {
+            if !fresh && now < self.next_heartbeat {
+                return vec![];
+            }
+            self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+            return self.accepts();
+        }
+        if now < self.election_deadline {
+            return vec![];
+        }
+        self.prepare(now)
+    }
+
+    /// propose takes the next slot for `command` if this node is the leader. It's sent to the
+    /// others on the next tick, along with whatever else was proposed in the meantime.
+    fn propose(&mut self, command: M::Command) -> Result<Applied<M::Output>, NotLeader> {
+        let Role::Leader { next_slot, .. } = &mut self.role else {
+            return Err(NotLeader {
+                leader: self.leader.clone(),
+            });
+        };
+        let slot = *next_slot;
+        *next_slot += 1;
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(slot, (self.promised.clone(), tx));
+        self.propose_slot(slot, Some(command));
+        Ok(rx)
+    }
+
+    /// handle answers a request from another node.
+    fn handle(&mut self, req: &Request<M::Command>, now: Instant) -> Reply<M::Command> {
+        match req {
+            Request::Prepare(p) => Reply::Promise(self.on_prepare(p, now)),
+            Request::Accept(a) => Reply::Accepted(self.on_accept(a, now)),
+        }
+    }
+
+    /// handle_reply takes in a peer's reply to one of our requests.
+    fn handle_reply(
+        &mut self,
+        from: &str,
+        reply: Reply<M::Command>,
+        now: Instant,
+    ) -> Outgoing<Request<M::Command>> {
+        if *reply.ballot() > self.promised {
+            self.observe(reply.ballot());
+            return vec![];
+        }
+        if *reply.ballot() < self.promised {
+            return vec![];
+        }
+
+        match (&mut self.role, reply) {
+            (
+                Role::Candidate {
+                    promises,
+                    recovered,
+                },
+                Reply::Promise(r),
+            ) if r.ok => {
+                promises.insert(from.to_string());
+                for slot in r.accepted {
+                    match recovered.get(&slot.slot) {
+                        Some(s) if s.ballot >= slot.ballot => {}
+                        _ => {
+                            recovered.insert(slot.slot, slot);
+                        }
+                    }
+                }
+                if promises.len() >= self.quorum() {
+                    return self.become_leader(now);
+                }
+                vec![]
+            }
+            (
+                Role::Leader {
+                    proposals, learned, ..
+                },
+                Reply::Accepted(r),
+            ) if r.ok => {
+                if let Some(l) = learned.get_mut(from) {
+                    *l = (*l).max(r.learned);
+                }
+                for &slot in &r.slots {
+                    if let Some((_, voters)) = proposals.get_mut(&slot) {
+                        voters.insert(from.to_string());
+                    }
+                }
+                for slot in r.slots {
+                    self.tally(slot);
+                }
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn reply_type(req: &Request<M::Command>) -> &'static str {
+        req.reply_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Log is a state machine that remembers every command and returns how many there have been.
+    #[derive(Debug, Default)]
+    struct Log(Vec<u64>);
+
+    impl StateMachine for Log {
+        type Command = u64;
+        type Output = usize;
+        type Snapshot = Vec<u64>;
+
+        fn apply(&mut self, command: &u64) -> usize {
+            self.0.push(*command);
+            self.0.len()
+        }
+
+        fn snapshot(&self) -> Vec<u64> {
+            self.0.clone()
+        }
+
+        fn restore(&mut self, snapshot: Vec<u64>) {
+            self.0 = snapshot;
+        }
+    }
+
+    // Cluster delivers messages between Paxos nodes straight away, except to and from the nodes
+    // that are cut off.
+    struct Cluster {
+        nodes: HashMap<String, Paxos<Log>>,
+        cut: HashSet<String>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: usize) -> Self {
+            let ids: Vec<String> = (1..=n).map(|i| format!("n{}", i)).collect();
+            let now = Instant::now();
+            let nodes = ids
+                .iter()
+                .map(|id| {
+                    let peers = ids.iter().filter(|p| *p != id).cloned().collect();
+                    let paxos = Paxos::new(id.clone(), peers, Log::default(), now);
+                    (id.clone(), paxos)
+                })
+                .collect();
+            Self {
+                nodes,
+                cut: HashSet::new(),
+                now,
+            }
+        }
+
+        fn node(&mut self, id: &str) -> &mut Paxos<Log> {
+            self.nodes.get_mut(id).unwrap()
+        }
+
+        // deliver sends `out` from `from` and everything that follows from it.
+        fn deliver(&mut self, from: &str, out: Outgoing<Request<u64>>) {
+            let mut queue: VecDeque<(String, String, Request<u64>)> = out
+                .into_iter()
+                .map(|(to, req)| (from.to_string(), to, req))
+                .collect();
+            while let Some((from, to, req)) = queue.pop_front() {
+                if self.cut.contains(&from) || self.cut.contains(&to) {
+                    continue;
+                }
+                let now = self.now;
+                let reply = self.node(&to).handle(&req, now);
+                let out = self.node(&from).handle_reply(&to, reply, now);
+                queue.extend(out.into_iter().map(|(t, r)| (from.clone(), t, r)));
+            }
+        }
+
+        // elect makes `id` prepare a ballot.
+        fn elect(&mut self, id: &str) {
+            let now = self.now;
+            let out = self.node(id).prepare(now);
+            self.deliver(id, out);
+        }
+
+        // heartbeat has `id` send its proposals and what's been chosen, if it's the leader.
+        fn heartbeat(&mut self, id: &str) {
+            let out = self.node(id).accepts();
+            self.deliver(id, out);
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let mut paxos = Paxos::new("n1".to_string(), vec![], Log::default(), Instant::now());
+        assert!(paxos.is_leader());
+        let mut applied = paxos.propose(7).expect("not the leader");
+        assert_eq!(applied.try_recv().unwrap().unwrap(), 1);
+        assert_eq!(paxos.machine().0, vec![7]);
+    }
+
+    #[test]
+    fn election() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+        assert!(c.node("n1").is_leader());
+        assert!(matches!(
+            c.node("n2").propose(1),
+            Err(NotLeader { leader: Some(l) }) if l == "n1"
+        ));
+
+        // A higher ballot takes over while n1 is cut off, and n1 steps down as soon as it hears
+        // of it.
+        c.cut.insert("n1".to_string());
+        c.elect("n2");
+        assert!(c.node("n2").is_leader());
+        assert_eq!(c.node("n2").ballot().round, 2);
+        c.cut.clear();
+        assert!(c.node("n1").is_leader());
+        c.heartbeat("n1");
+        assert!(!c.node("n1").is_leader());
+        let now = c.now;
+        assert!(c.node("n1").tick(now).is_empty());
+    }
+
+    #[test]
+    fn replication() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+        let mut applied: Vec<_> = (10..13).map(|i| c.node("n1").propose(i).unwrap()).collect();
+        assert!(applied[0].try_recv().is_err());
+
+        // Once a majority has accepted them they're chosen and applied on the leader, and the
+        // followers learn of it on the next heartbeat.
+        c.cut.insert("n3".to_string());
+        c.heartbeat("n1");
+        for (i, a) in applied.iter_mut().enumerate() {
+            assert_eq!(a.try_recv().unwrap().unwrap(), i + 1);
+        }
+        c.heartbeat("n1");
+        assert_eq!(c.node("n2").machine().0, vec![10, 11, 12]);
+        assert!(c.node("n3").machine().0.is_empty());
+
+        c.cut.clear();
+        c.heartbeat("n1");
+        assert_eq!(c.node("n3").machine().0, vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn recovery() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+
+        // n2 accepts slot 1 but n1 never hears back, so nobody knows it's chosen. Slot 2 only
+        // ever reaches n1.
+        let mut first = c.node("n1").propose(1).unwrap();
+        let out = c.node("n1").accepts();
+        let accept = out.into_iter().find(|(to, _)| to == "n2").unwrap().1;
+        let now = c.now;
+        c.node("n2").handle(&accept, now);
+        c.cut.insert("n1".to_string());
+        let mut second = c.node("n1").propose(2).unwrap();
+        c.heartbeat("n1");
+
+        // n3 learns of slot 1 from n2's promise and proposes it again. Nobody it heard from
+        // accepted anything for slot 2, so that's where its own command goes.
+        c.elect("n3");
+        let mut third = c.node("n3").propose(3).unwrap();
+        c.heartbeat("n3");
+        assert_eq!(third.try_recv().unwrap().unwrap(), 2);
+        assert_eq!(c.node("n3").machine().0, vec![1, 3]);
+
+        // n1 was outbid, so it can't tell whether its commands were chosen.
+        c.cut.clear();
+        c.heartbeat("n3");
+        assert!(!c.node("n1").is_leader());
+        assert_eq!(c.node("n1").machine().0, vec![1, 3]);
+        for lost in [&mut first, &mut second] {
+            let e = lost.try_recv().unwrap().unwrap_err();
+            assert_eq!(e.code, ErrorCode::Crash);
+        }
+    }
+}
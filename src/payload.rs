@@ -8,6 +8,18 @@ pub struct Payload<T> {
     pub body: T,
 }
 
+impl<T> Payload<T> {
+    /// map converts the body while keeping the addressing, e.g., to wrap a reply into a
+    /// workload's response enum.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Payload<U> {
+        Payload {
+            src: self.src,
+            dest: self.dest,
+            body: f(self.body),
+        }
+    }
+}
+
 // Implement Serialize only when T is Serialize
 impl<T> Serialize for Payload<T>
 where
@@ -51,15 +63,38 @@ where
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-#[serde(rename_all = "lowercase")]
-#[serde(tag = "type")]
+// RequestBody wraps a workload's tagged body so that `msg_id` bookkeeping lives in one place.
+// The workload's `type` tag is flattened alongside it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RequestBody<T> {
     pub msg_id: u32,
     #[serde(flatten)]
     pub data: T,
 }
 
+// Message is what a `node::Handler` receives.
+pub type Message<T> = Payload<RequestBody<T>>;
+
+impl<T> Payload<RequestBody<T>> {
+    /// reply addresses a response back to the sender of this message.
+    pub fn reply<D>(&self, typ: &str, data: Option<D>) -> Payload<ResponseBody<D>> {
+        Payload {
+            src: self.dest.clone(),
+            dest: self.src.clone(),
+            body: ResponseBody {
+                typ: typ.to_string(),
+                in_reply_to: self.body.msg_id,
+                data,
+            },
+        }
+    }
+
+    /// error addresses an `error` reply back to the sender of this message.
+    pub fn error(&self, e: ErrorBody) -> Payload<ResponseBody<ErrorBody>> {
+        self.reply("error", Some(e))
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
 #[serde(tag = "type")]
@@ -70,4 +105,140 @@ pub struct ResponseBody<T> {
     #[serde(flatten)]
     pub data: Option<T>,
 }
-pub type UnhandledMessage = std::collections::HashMap<String, serde_json::Value>;
+
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    Timeout,
+    NodeNotFound,
+    NotSupported,
+    TemporarilyUnavailable,
+    MalformedRequest,
+    Crash,
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    PreconditionFailed,
+    TxnConflict,
+    // Maelstrom leaves codes 1000 and up for workloads to define their own.
+    Custom(u32),
+}
+
+impl From<u32> for ErrorCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            c => ErrorCode::Custom(c),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(c) => c,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// definite is whether the operation is known to not have taken place. Timeouts and crashes
+    /// are indefinite, the operation may or may not have been applied.
+    pub fn definite(&self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+}
+
+// ErrorBody is the data of an `error` reply. It's also an error in its own right so that a
+// `node::Handler` can return it and have the node reply with it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: Some(text.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "error {:?}: {}", self.code, text),
+            None => write!(f, "error {:?}", self.code),
+        }
+    }
+}
+
+impl std::error::Error for ErrorBody {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_body() {
+        let test_cases = vec![
+            (
+                ErrorBody::new(ErrorCode::NotSupported, "unsupported message type"),
+                r#"{"code":10,"text":"unsupported message type"}"#,
+            ),
+            (
+                ErrorBody {
+                    code: ErrorCode::PreconditionFailed,
+                    text: None,
+                },
+                r#"{"code":22}"#,
+            ),
+            (
+                ErrorBody::new(ErrorCode::Custom(1001), "custom"),
+                r#"{"code":1001,"text":"custom"}"#,
+            ),
+        ];
+
+        for (body, expected) in test_cases {
+            let actual = serde_json::to_string(&body).expect("failed to serialize");
+            assert_eq!(actual, expected);
+
+            let parsed: ErrorBody = serde_json::from_str(expected).expect("failed to deserialize");
+            assert_eq!(parsed, body);
+        }
+    }
+
+    #[test]
+    fn error_code_round_trip() {
+        for code in [0, 1, 10, 11, 12, 13, 14, 20, 21, 22, 30, 1000] {
+            assert_eq!(u32::from(ErrorCode::from(code)), code);
+        }
+        assert!(ErrorCode::KeyDoesNotExist.definite());
+        assert!(!ErrorCode::Timeout.definite());
+    }
+}
@@ -0,0 +1,202 @@
+use crate::store::{self, Store};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+// Persister keeps what a consensus module needs to get back after a crash: a small piece of
+// state that's overwritten as a whole (e.g., Raft's term and vote), a snapshot of everything up to
+// some index, and a log of entries by index.
+//
+// The state and the snapshot are kept in files of their own under a directory, `state.json` and
+// `snapshot.json`, that are replaced atomically with `store::write_atomic`.
+//
+// The log is written through a `Store` as JSON lines of `{"index": .., "entry": ..}` and synced
+// after every append. The store is only ever appended to, so replacing entries is done by
+// appending the new ones at their index: replaying the log drops everything from an index on
+// whenever it comes across that index again. A crash in the middle of an append can leave a
+// torn last line, which is skipped --- it was never synced, so it was never acknowledged.
+//
+// Once a snapshot covers the start of the log, the log is compacted by atomically replacing the
+// whole store with just the entries after it, so the first entry isn't necessarily at index 1.
+
+#[derive(Serialize, Deserialize)]
+struct Record<E> {
+    index: u64,
+    entry: E,
+}
+
+pub struct Persister<S: Store> {
+    log: S,
+    dir: PathBuf,
+}
+
+impl<S: Store> Persister<S> {
+    /// new writes the log to `log` and keeps the state and snapshot under `dir`.
+    pub fn new(log: S, dir: PathBuf) -> Self {
+        Self { log, dir }
+    }
+
+    /// load_state returns the last state that was saved, if there ever was one.
+    pub fn load_state<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        load(&self.dir.join("state.json"))
+    }
+
+    /// save_state durably replaces the state.
+    pub fn save_state<T: Serialize>(&mut self, state: &T) -> Result<(), Error> {
+        store::write_atomic(&self.dir.join("state.json"), &serde_json::to_vec(state)?)
+    }
+
+    /// load_snapshot returns the last snapshot that was saved, if there ever was one.
+    pub fn load_snapshot<T: DeserializeOwned>(&self) -> Result<Option<T>, Error> {
+        load(&self.dir.join("snapshot.json"))
+    }
+
+    /// save_snapshot durably replaces the snapshot. It's up to the caller to `compact` the log
+    /// once it's saved.
+    pub fn save_snapshot<T: Serialize>(&mut self, snapshot: &T) -> Result<(), Error> {
+        store::write_atomic(
+            &self.dir.join("snapshot.json"),
+            &serde_json::to_vec(snapshot)?,
+        )
+    }
+
+    /// append durably writes `entries` starting at `index`, which replaces whatever was at
+    /// `index` and after.
+    pub fn append<E: Serialize>(&mut self, index: u64, entries: &[E]) -> Result<(), Error> {
+        self.log.write_all(&records(index, entries)?)?;
+        self.log.sync()
+    }
+
+    /// compact replaces the whole log with `entries`, starting at `index`.
+    pub fn compact<E: Serialize>(&mut self, index: u64, entries: &[E]) -> Result<(), Error> {
+        self.log.replace(&records(index, entries)?)
+    }
+
+    /// replay reads the log back from the start, and returns the index of its first entry along
+    /// with the entries.
+    pub fn replay<E: DeserializeOwned>(&mut self) -> Result<(u64, Vec<E>), Error> {
+        let mut first = None;
+        let mut entries: Vec<E> = Vec::new();
+        let mut torn = false;
+        for line in (&mut self.log).lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let record: Record<E> = match serde_json::from_str(&line) {
+                Ok(record) => {
+                    torn = false;
+                    record
+                }
+                Err(e) => {
+                    warn!("skipping torn log record: {}", e);
+                    torn = true;
+                    continue;
+                }
+            };
+            let first = *first.get_or_insert(record.index);
+            let next = first + entries.len() as u64;
+            if record.index < first || record.index > next {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("log skips from {} to {}", next - 1, record.index),
+                ));
+            }
+            entries.truncate((record.index - first) as usize);
+            entries.push(record.entry);
+        }
+        // Whatever's appended after a torn last line has to start on a line of its own.
+        if torn {
+            self.log.write_all(b"\n")?;
+            self.log.sync()?;
+        }
+        Ok((first.unwrap_or(1), entries))
+    }
+}
+
+fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    match std::fs::read(path) {
+        Ok(b) => Ok(Some(serde_json::from_slice(&b)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// records serializes `entries` as log records starting at `index`.
+fn records<E: Serialize>(index: u64, entries: &[E]) -> Result<Vec<u8>, Error> {
+    let mut b = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let record = Record {
+            index: index + i as u64,
+            entry,
+        };
+        serde_json::to_writer(&mut b, &record)?;
+        b.push(b'\n');
+    }
+    Ok(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::FileStore;
+    use std::io::Write;
+
+    #[test]
+    fn state() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let log = FileStore::new(dir.path().join("log")).unwrap();
+        let mut p = Persister::new(log, dir.path().to_path_buf());
+        assert_eq!(p.load_state::<(u64, String)>().unwrap(), None);
+        p.save_state(&(1, "n1")).unwrap();
+        p.save_state(&(2, "n2")).unwrap();
+        assert_eq!(
+            p.load_state::<(u64, String)>().unwrap(),
+            Some((2, "n2".to_string()))
+        );
+        assert_eq!(p.load_snapshot::<Vec<u64>>().unwrap(), None);
+        p.save_snapshot(&vec![1, 2]).unwrap();
+        assert_eq!(p.load_snapshot::<Vec<u64>>().unwrap(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn replay() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let open = || {
+            let log = FileStore::new(dir.path().join("log")).unwrap();
+            Persister::new(log, dir.path().to_path_buf())
+        };
+
+        let mut p = open();
+        assert_eq!(p.replay::<u64>().unwrap(), (1, vec![]));
+        p.append(1, &[10u64, 20, 30]).unwrap();
+        // Replaces 20 and 30.
+        p.append(2, &[21u64]).unwrap();
+        p.append(3, &[31u64]).unwrap();
+        drop(p);
+
+        // A crash while appending leaves half a line.
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("log"))
+            .unwrap();
+        f.write_all(br#"{"index":4,"en"#).unwrap();
+
+        let mut p = open();
+        assert_eq!(p.replay::<u64>().unwrap(), (1, vec![10, 21, 31]));
+        p.append(4, &[41u64]).unwrap();
+        let mut p = open();
+        assert_eq!(p.replay::<u64>().unwrap(), (1, vec![10, 21, 31, 41]));
+
+        // Once compacted the log starts later on, and carries on from there.
+        p.compact(3, &[31u64, 41]).unwrap();
+        p.append(5, &[51u64]).unwrap();
+        let mut p = open();
+        assert_eq!(p.replay::<u64>().unwrap(), (3, vec![31, 41, 51]));
+        p.compact::<u64>(6, &[]).unwrap();
+        let mut p = open();
+        assert_eq!(p.replay::<u64>().unwrap(), (1, vec![]));
+    }
+}
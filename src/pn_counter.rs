@@ -0,0 +1,168 @@
+use crate::counter::{self, GCounter};
+use crate::payload::{Message, Payload, ResponseBody};
+use crate::{config, node, store};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Goals(s):
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-pn-counter
+// - Like the g-counter, but `add` may be given a negative delta.
+//
+// A PN-Counter is two G-Counters, one for everything that was added and one for everything that
+// was taken away. Each only ever grows, so they can be gossiped and merged exactly like the
+// g-counter's `crdt` mode, and the value is the difference between them.
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadData {
+    value: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "lowercase")]
+pub enum RequestBody {
+    Add { delta: i64 },
+    Read,
+    Gossip { counts: PNCounter },
+}
+
+/// PNCounter is a counter CRDT that can be decremented as well as incremented.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    inc: GCounter,
+    dec: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.inc.add(node, delta.unsigned_abs());
+        } else {
+            self.dec.add(node, delta.unsigned_abs());
+        }
+    }
+
+    pub fn merge(&mut self, other: &PNCounter) {
+        self.inc.merge(&other.inc);
+        self.dec.merge(&other.dec);
+    }
+
+    pub fn value(&self) -> i64 {
+        self.inc.value() as i64 - self.dec.value() as i64
+    }
+}
+
+pub struct Counter {
+    state: Arc<Mutex<PNCounter>>,
+    interval: Duration,
+}
+
+impl Default for Counter {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            interval: Duration::from_millis(500),
+        }
+    }
+}
+
+impl Counter {
+    /// with_interval sets how often the state is gossiped.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+impl<S, T> node::Handler<S, T> for Counter
+where
+    T: config::TimeSource,
+    S: store::Store,
+{
+    type Request = RequestBody;
+    type Response = ResponseBody<ReadData>;
+
+    // Gossip starts once the node knows who its peers are.
+    fn init(&mut self, node: &mut node::Node<S, T>) -> Result<()> {
+        counter::start_gossip(node, self.interval, &self.state, |counts| {
+            RequestBody::Gossip { counts }
+        });
+        Ok(())
+    }
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        let mut state = self.state.lock().expect("failed to take counter lock");
+        match &msg.body.data {
+            RequestBody::Add { delta } => {
+                state.add(&node.id, *delta);
+                Ok(vec![msg.reply("add_ok", None)])
+            }
+            RequestBody::Read => Ok(vec![msg.reply(
+                "read_ok",
+                Some(ReadData {
+                    value: state.value(),
+                }),
+            )]),
+            RequestBody::Gossip { counts } => {
+                state.merge(counts);
+                Ok(vec![])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time};
+
+    #[test]
+    fn merge() {
+        let mut a = PNCounter::default();
+        a.add("n1", 5);
+        a.add("n1", -7);
+        let mut b = PNCounter::default();
+        b.add("n2", -1);
+
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a, b);
+        assert_eq!(a.value(), -3);
+    }
+
+    #[tokio::test]
+    async fn pn_counter() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}
+{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":2}}
+{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":-5}}
+{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":1,"counts":{"inc":{"n2":1},"dec":{"n2":4}}}}
+{"src":"c1","dest":"n1","body":{"type":"read","msg_id":4}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":2}}
+{"src":"n1","dest":"c1","body":{"type":"add_ok","in_reply_to":3}}
+{"src":"n1","dest":"c1","body":{"type":"read_ok","in_reply_to":4,"value":-6}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        // Nothing is gossiped for as long as the test runs.
+        let counter = Counter::default().with_interval(Duration::from_secs(3600));
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(Cursor::new(input.as_bytes()), &mut actual, counter)
+            .await
+            .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+}
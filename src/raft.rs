@@ -0,0 +1,1139 @@
+use crate::consensus::{Applied, NotLeader, Outgoing, Protocol, Resolver, StateMachine};
+use crate::payload::{ErrorBody, ErrorCode};
+use crate::persist::Persister;
+use crate::store::{FileStore, Store};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::{debug, info};
+
+// Raft, as described in "In Search of an Understandable Consensus Algorithm":
+// https://raft.github.io/raft.pdf
+//
+// Every node keeps a log of commands for a state machine. One node at a time is elected leader
+// for a term, and it's the only one that appends to the log. It replicates its log to the others
+// with `append_entries`, and an entry is committed once a majority has it. Committed entries
+// are applied to the state machine in log order on every node, so every node's state machine
+// goes through the same states.
+//
+// A follower that doesn't hear from a leader for an election timeout becomes a candidate for the
+// next term and asks everyone else to vote for it with `request_vote`. Each node votes at most
+// once per term and only for candidates whose log is at least as up to date as its own, which
+// makes sure a leader has every committed entry. A newly elected leader appends an empty entry
+// so that entries from earlier terms are committed without waiting for a client.
+//
+// `Raft` is a `consensus::Protocol`, so it's run on the node's RPC layer by `consensus::Replica`.
+//
+// Once enough entries have been applied, the state machine is snapshotted and the log is
+// truncated up to the last entry in the snapshot. A follower that's fallen behind the start of the
+// leader's log is sent the snapshot whole with `install_snapshot` and continues from there.
+//
+// A node's term, vote, snapshot and log have to survive it crashing, or it could vote twice in a
+// term or forget entries it told the leader it had. They're written through a `Storage` before
+// the node acts on them, i.e., before it replies or sends anything that depends on them, and
+// they're read back when it starts.
+
+// ELECTION_TIMEOUT is the least time a follower waits to hear from a leader before standing
+// for election. Each wait is randomized up to twice that so that candidates don't keep splitting
+// the vote.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+
+// HEARTBEAT_INTERVAL is how often the leader sends `append_entries` even when there's nothing
+// new, which is what keeps followers from standing for election.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+// MAX_ENTRIES caps how many entries a single `append_entries` carries.
+const MAX_ENTRIES: usize = 100;
+
+// SNAPSHOT_AFTER is how many entries are applied after a snapshot before the next one is taken.
+const SNAPSHOT_AFTER: u64 = 1000;
+
+/// Snapshot is the state machine as of having applied every entry up to `last_index`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot<S> {
+    pub last_index: u64,
+    pub last_term: u64,
+    pub data: S,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry<C> {
+    pub term: u64,
+    // `None` is the empty entry a leader appends when it's elected.
+    pub command: Option<C>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteData {
+    term: u64,
+    candidate: String,
+    last_log_index: u64,
+    last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VoteReply {
+    term: u64,
+    granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendData<C> {
+    term: u64,
+    leader: String,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<Entry<C>>,
+    leader_commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppendReply {
+    term: u64,
+    success: bool,
+    // On success, the index of the last entry known to match the leader's log. Otherwise a hint
+    // of where the follower's log might match it.
+    last_index: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotData<S> {
+    term: u64,
+    leader: String,
+    #[serde(flatten)]
+    snapshot: Snapshot<S>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotReply {
+    term: u64,
+    // The index of the last entry the follower has, at least as of the snapshot.
+    last_index: u64,
+}
+
+/// Request is a message one Raft node sends another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Request<C, S> {
+    RequestVote(VoteData),
+    AppendEntries(AppendData<C>),
+    InstallSnapshot(SnapshotData<S>),
+}
+
+impl<C, S> Request<C, S> {
+    /// reply_type is the type of the reply to the request.
+    pub fn reply_type(&self) -> &'static str {
+        match self {
+            Request::RequestVote(_) => "request_vote_ok",
+            Request::AppendEntries(_) => "append_entries_ok",
+            Request::InstallSnapshot(_) => "install_snapshot_ok",
+        }
+    }
+}
+
+/// RequestOf is the [`Request`] Raft nodes replicating `M` send each other.
+pub type RequestOf<M> = Request<<M as StateMachine>::Command, <M as StateMachine>::Snapshot>;
+
+// Replies are told apart by their fields: only votes have `granted` and only appends have
+// `success`. A snapshot reply's fields are all in an append's too, so it has to go last.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Reply {
+    Vote(VoteReply),
+    Append(AppendReply),
+    Snapshot(SnapshotReply),
+}
+
+impl Reply {
+    fn term(&self) -> u64 {
+        match self {
+            Reply::Vote(r) => r.term,
+            Reply::Append(r) => r.term,
+            Reply::Snapshot(r) => r.term,
+        }
+    }
+}
+
+/// HardState is the part of a node's state, besides its log, that has to be durable.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+/// Saved is what a node kept from before it was restarted.
+pub struct Saved<C, S> {
+    pub state: HardState,
+    pub snapshot: Option<Snapshot<S>>,
+    /// The index of the first entry in `log`.
+    pub first_index: u64,
+    pub log: Vec<Entry<C>>,
+}
+
+/// Storage is where Raft keeps its [`HardState`], snapshot and log.
+pub trait Storage<C, S>: Send {
+    /// load reads back whatever was saved, or nothing for a node that's never run.
+    fn load(&mut self) -> io::Result<Saved<C, S>>;
+    fn save_state(&mut self, state: &HardState) -> io::Result<()>;
+    /// append writes `entries` from `index` on, replacing whatever was there.
+    fn append(&mut self, index: u64, entries: &[Entry<C>]) -> io::Result<()>;
+    /// save_snapshot replaces the snapshot, and then the log with the entries that follow it.
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S>, log: &[Entry<C>]) -> io::Result<()>;
+}
+
+/// Volatile keeps nothing, for nodes that don't need to survive a crash.
+pub struct Volatile;
+
+impl<C, S> Storage<C, S> for Volatile {
+    fn load(&mut self) -> io::Result<Saved<C, S>> {
+        Ok(Saved {
+            state: HardState::default(),
+            snapshot: None,
+            first_index: 1,
+            log: Vec::new(),
+        })
+    }
+
+    fn save_state(&mut self, _: &HardState) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn append(&mut self, _: u64, _: &[Entry<C>]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, _: &Snapshot<S>, _: &[Entry<C>]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The snapshot is saved before the log is replaced, so a crash in between leaves a log that
+// starts before the snapshot, and the entries the snapshot covers are skipped on load.
+impl<C, S, St> Storage<C, S> for Persister<St>
+where
+    C: Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+    St: Store + Send,
+{
+    fn load(&mut self) -> io::Result<Saved<C, S>> {
+        let state = self.load_state()?.unwrap_or_default();
+        let snapshot = self.load_snapshot()?;
+        let (first_index, log) = self.replay()?;
+        Ok(Saved {
+            state,
+            snapshot,
+            first_index,
+            log,
+        })
+    }
+
+    fn save_state(&mut self, state: &HardState) -> io::Result<()> {
+        Persister::save_state(self, state)
+    }
+
+    fn append(&mut self, index: u64, entries: &[Entry<C>]) -> io::Result<()> {
+        Persister::append(self, index, entries)
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot<S>, log: &[Entry<C>]) -> io::Result<()> {
+        Persister::save_snapshot(self, snapshot)?;
+        self.compact(snapshot.last_index + 1, log)
+    }
+}
+
+// persist panics if the state couldn't be made durable, since carrying on could have the node
+// break its promises after a restart.
+fn persist(res: io::Result<()>) {
+    if let Err(e) = res {
+        panic!("failed to persist raft state: {}", e);
+    }
+}
+
+#[derive(Debug)]
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        // The next entry to send to each peer.
+        next_index: HashMap<String, u64>,
+        // The last entry each peer is known to have.
+        match_index: HashMap<String, u64>,
+    },
+}
+
+/// Raft is one node's part of the algorithm, with no I/O of its own.
+pub struct Raft<M: StateMachine> {
+    id: String,
+    peers: Vec<String>,
+    term: u64,
+    voted_for: Option<String>,
+    // The latest snapshot, which the log carries on from.
+    snapshot: Option<Snapshot<M::Snapshot>>,
+    snapshot_after: u64,
+    // Entry `i` is at `log[i - snapshot_index - 1]`. The first entry is 1, so 0 means none.
+    log: Vec<Entry<M::Command>>,
+    commit_index: u64,
+    last_applied: u64,
+    role: Role,
+    leader: Option<String>,
+    election_deadline: Instant,
+    next_heartbeat: Instant,
+    machine: M,
+    // Proposals waiting to be applied by index, with the term they were proposed in.
+    pending: HashMap<u64, (u64, Resolver<M::Output>)>,
+    storage: Box<dyn Storage<M::Command, M::Snapshot>>,
+}
+
+impl<M: StateMachine> Raft<M> {
+    /// new starts a follower from whatever `storage` has kept. A node without peers is its own
+    /// majority, so it leads right away.
+    pub fn new(
+        id: String,
+        peers: Vec<String>,
+        mut machine: M,
+        mut storage: Box<dyn Storage<M::Command, M::Snapshot>>,
+        now: Instant,
+    ) -> io::Result<Self> {
+        let Saved {
+            state,
+            snapshot,
+            first_index,
+            mut log,
+        } = storage.load()?;
+
+        let snapshot_index = snapshot.as_ref().map_or(0, |s| s.last_index);
+        if let Some(snapshot) = &snapshot {
+            machine.restore(snapshot.data.clone());
+        }
+        // Whatever the snapshot covers is skipped, but there can't be a gap after it.
+        if !log.is_empty() {
+            if first_index > snapshot_index + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "log starts at {} but the snapshot ends at {}",
+                        first_index, snapshot_index
+                    ),
+                ));
+            }
+            let covered = (snapshot_index + 1 - first_index) as usize;
+            log.drain(..covered.min(log.len()));
+        }
+        if state.term > 0 {
+            info!(
+                "restored term {}, a snapshot up to {} and {} log entries",
+                state.term,
+                snapshot_index,
+                log.len()
+            );
+        }
+
+        let mut raft = Self {
+            id,
+            peers,
+            term: state.term,
+            voted_for: state.voted_for,
+            snapshot,
+            snapshot_after: SNAPSHOT_AFTER,
+            log,
+            // The snapshot was committed and applied, but nothing after it is known to be.
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            role: Role::Follower,
+            leader: None,
+            election_deadline: now,
+            next_heartbeat: now,
+            machine,
+            pending: HashMap::new(),
+            storage,
+        };
+        raft.reset_election(now);
+        if raft.peers.is_empty() {
+            raft.start_election(now);
+        }
+        Ok(raft)
+    }
+
+    pub fn machine(&self) -> &M {
+        &self.machine
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    /// with_snapshot_after takes a snapshot every `entries` applied entries.
+    pub fn with_snapshot_after(mut self, entries: u64) -> Self {
+        self.snapshot_after = entries;
+        self
+    }
+
+    fn snapshot_index(&self) -> u64 {
+        self.snapshot.as_ref().map_or(0, |s| s.last_index)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index() + self.log.len() as u64
+    }
+
+    // entry returns the entry at `index`, which has to be after the snapshot.
+    fn entry(&self, index: u64) -> &Entry<M::Command> {
+        &self.log[(index - self.snapshot_index() - 1) as usize]
+    }
+
+    // term_at returns the term of the entry at `index`, which can't be before the snapshot.
+    fn term_at(&self, index: u64) -> u64 {
+        match &self.snapshot {
+            Some(s) if index == s.last_index => s.last_term,
+            None if index == 0 => 0,
+            _ => self.entry(index).term,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    fn reset_election(&mut self, now: Instant) {
+        let wait = ELECTION_TIMEOUT.mul_f64(rand::rng().random_range(1.0..2.0));
+        self.election_deadline = now + wait;
+    }
+
+    fn save_state(&mut self) {
+        persist(self.storage.save_state(&HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+        }));
+    }
+
+    // append_log replaces the log from `index` on with `entries`.
+    fn append_log(&mut self, index: u64, entries: Vec<Entry<M::Command>>) {
+        persist(self.storage.append(index, &entries));
+        self.log
+            .truncate((index - self.snapshot_index() - 1) as usize);
+        self.log.extend(entries);
+    }
+
+    // install makes `snapshot` the latest one, dropping the log entries it covers.
+    fn install(&mut self, snapshot: Snapshot<M::Snapshot>) {
+        let covered = snapshot.last_index - self.snapshot_index();
+        self.log.drain(..(covered as usize).min(self.log.len()));
+        persist(self.storage.save_snapshot(&snapshot, &self.log));
+        self.snapshot = Some(snapshot);
+    }
+
+    // observe moves to `term` as a follower if it's later than the current one.
+    fn observe(&mut self, term: u64) {
+        if term > self.term {
+            debug!("stepping down for term {}", term);
+            self.term = term;
+            self.voted_for = None;
+            self.role = Role::Follower;
+            self.save_state();
+        }
+    }
+
+    fn start_election(&mut self, now: Instant) -> Outgoing<RequestOf<M>> {
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.save_state();
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id.clone()]),
+        };
+        self.reset_election(now);
+        info!("standing for election in term {}", self.term);
+
+        if self.quorum() == 1 {
+            return self.become_leader(now);
+        }
+        let vote = VoteData {
+            term: self.term,
+            candidate: self.id.clone(),
+            last_log_index: self.last_index(),
+            last_log_term: self.term_at(self.last_index()),
+        };
+        self.peers
+            .iter()
+            .map(|p| (p.clone(), Request::RequestVote(vote.clone())))
+            .collect()
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Outgoing<RequestOf<M>> {
+        info!("elected leader for term {}", self.term);
+        let next = self.last_index() + 1;
+        self.role = Role::Leader {
+            next_index: self.peers.iter().map(|p| (p.clone(), next)).collect(),
+            match_index: self.peers.iter().map(|p| (p.clone(), 0)).collect(),
+        };
+        self.leader = Some(self.id.clone());
+        self.append_log(
+            self.last_index() + 1,
+            vec![Entry {
+                term: self.term,
+                command: None,
+            }],
+        );
+        self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+        self.advance_commit();
+        self.replicate()
+    }
+
+    // replicate sends every peer the entries it's missing, or a heartbeat if it's up to date.
+    fn replicate(&self) -> Outgoing<RequestOf<M>> {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return vec![];
+        };
+        self.peers
+            .iter()
+            .map(|p| (p.clone(), self.append_to(next_index[p])))
+            .collect()
+    }
+
+    // append_to sends the entries from `next` on, or the snapshot if they're not in the log any
+    // more.
+    fn append_to(&self, next: u64) -> RequestOf<M> {
+        if let Some(snapshot) = self.snapshot.as_ref().filter(|s| next <= s.last_index) {
+            return Request::InstallSnapshot(SnapshotData {
+                term: self.term,
+                leader: self.id.clone(),
+                snapshot: snapshot.clone(),
+            });
+        }
+        let prev = next - 1;
+        let start = (prev - self.snapshot_index()) as usize;
+        let end = self.log.len().min(start + MAX_ENTRIES);
+        Request::AppendEntries(AppendData {
+            term: self.term,
+            leader: self.id.clone(),
+            prev_log_index: prev,
+            prev_log_term: self.term_at(prev),
+            entries: self.log[start..end].to_vec(),
+            leader_commit: self.commit_index,
+        })
+    }
+
+    fn on_request_vote(&mut self, v: &VoteData, now: Instant) -> VoteReply {
+        self.observe(v.term);
+        let last = self.last_index();
+        let up_to_date = (v.last_log_term, v.last_log_index) >= (self.term_at(last), last);
+        let granted = v.term == self.term
+            && self.voted_for.as_ref().is_none_or(|c| *c == v.candidate)
+            && up_to_date;
+        if granted {
+            debug!("voting for {} in term {}", v.candidate, v.term);
+            self.voted_for = Some(v.candidate.clone());
+            self.save_state();
+            self.reset_election(now);
+        }
+        VoteReply {
+            term: self.term,
+            granted,
+        }
+    }
+
+    // follow makes this node a follower of `leader` for the current term.
+    fn follow(&mut self, term: u64, leader: &str, now: Instant) {
+        self.observe(term);
+        // A candidate that hears from the leader of its own term lost the election.
+        self.role = Role::Follower;
+        self.leader = Some(leader.to_string());
+        self.reset_election(now);
+    }
+
+    fn on_append_entries(&mut self, a: &AppendData<M::Command>, now: Instant) -> AppendReply {
+        if a.term < self.term {
+            return AppendReply {
+                term: self.term,
+                success: false,
+                last_index: self.last_index(),
+            };
+        }
+        self.follow(a.term, &a.leader, now);
+
+        // Entries the snapshot covers were committed, so they match whatever the leader has.
+        let (prev, prev_term, entries) = match self.snapshot_index().checked_sub(a.prev_log_index) {
+            // A request that was delayed until after the snapshot has nothing new in it.
+            Some(covered) if covered > 0 && covered as usize >= a.entries.len() => {
+                return AppendReply {
+                    term: self.term,
+                    success: true,
+                    last_index: self.snapshot_index(),
+                };
+            }
+            Some(covered) if covered > 0 => {
+                let covered = covered as usize;
+                let prev = a.prev_log_index + covered as u64;
+                (prev, self.term_at(prev), &a.entries[covered..])
+            }
+            _ => (a.prev_log_index, a.prev_log_term, &a.entries[..]),
+        };
+        if prev > self.last_index() || self.term_at(prev) != prev_term {
+            return AppendReply {
+                term: self.term,
+                success: false,
+                last_index: self.last_index().min(prev.saturating_sub(1)),
+            };
+        }
+
+        // Entries the log already has are skipped, and everything from the first one that
+        // disagrees with the leader goes.
+        let new = entries.iter().enumerate().find(|(i, entry)| {
+            let index = prev + 1 + *i as u64;
+            index > self.last_index() || self.term_at(index) != entry.term
+        });
+        if let Some((i, _)) = new {
+            let index = prev + 1 + i as u64;
+            if index <= self.last_index() {
+                debug!("truncating log from {}", index);
+            }
+            self.append_log(index, entries[i..].to_vec());
+        }
+
+        let matched = prev + entries.len() as u64;
+        if a.leader_commit > self.commit_index {
+            self.commit_index = a.leader_commit.min(matched);
+            self.apply();
+        }
+        AppendReply {
+            term: self.term,
+            success: true,
+            last_index: matched,
+        }
+    }
+
+    fn on_install_snapshot(
+        &mut self,
+        s: &SnapshotData<M::Snapshot>,
+        now: Instant,
+    ) -> SnapshotReply {
+        if s.term < self.term {
+            return SnapshotReply {
+                term: self.term,
+                last_index: self.last_index(),
+            };
+        }
+        self.follow(s.term, &s.leader, now);
+
+        let snapshot = &s.snapshot;
+        // Everything up to the commit index already matches the leader.
+        if snapshot.last_index <= self.commit_index {
+            return SnapshotReply {
+                term: self.term,
+                last_index: snapshot.last_index,
+            };
+        }
+        info!(
+            "installing snapshot up to {} from {}",
+            snapshot.last_index, s.leader
+        );
+
+        // The entries after the snapshot are only kept if the log agrees with it, otherwise none
+        // of them can be trusted.
+        let agrees = snapshot.last_index <= self.last_index()
+            && self.term_at(snapshot.last_index) == snapshot.last_term;
+        if !agrees {
+            self.log.clear();
+        }
+        self.install(snapshot.clone());
+        self.machine.restore(snapshot.data.clone());
+        self.commit_index = snapshot.last_index;
+        self.last_applied = snapshot.last_index;
+
+        // Whether proposals from when this node led made it into the snapshot can't be told.
+        let lost: Vec<u64> = self
+            .pending
+            .keys()
+            .copied()
+            .filter(|&i| i <= snapshot.last_index)
+            .collect();
+        for index in lost {
+            let (_, tx) = self.pending.remove(&index).expect("pending proposal");
+            let _ = tx.send(Err(ErrorBody::new(
+                ErrorCode::Crash,
+                "caught up from a snapshot, the command may or may not have been committed",
+            )));
+        }
+
+        SnapshotReply {
+            term: self.term,
+            last_index: snapshot.last_index,
+        }
+    }
+
+    // advance_commit commits the latest entry of the current term that a majority has.
+    fn advance_commit(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        for n in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(n) != self.term {
+                break;
+            }
+            let have = 1 + match_index.values().filter(|&&m| m >= n).count();
+            if have >= self.quorum() {
+                self.commit_index = n;
+                break;
+            }
+        }
+        self.apply();
+    }
+
+    // apply runs every committed entry that hasn't been yet through the state machine, and takes
+    // a snapshot once enough have been.
+    fn apply(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let index = self.last_applied;
+            let entry = &self.log[(index - self.snapshot_index() - 1) as usize];
+            let output = entry.command.as_ref().map(|c| self.machine.apply(c));
+
+            let Some((term, tx)) = self.pending.remove(&index) else {
+                continue;
+            };
+            let res = match output {
+                Some(output) if term == entry.term => Ok(output),
+                // Another leader's entry took its place.
+                _ => Err(ErrorBody::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    "leadership was lost before the command was committed",
+                )),
+            };
+            let _ = tx.send(res);
+        }
+
+        if self.last_applied - self.snapshot_index() >= self.snapshot_after {
+            debug!("taking a snapshot up to {}", self.last_applied);
+            self.install(Snapshot {
+                last_index: self.last_applied,
+                last_term: self.term_at(self.last_applied),
+                data: self.machine.snapshot(),
+            });
+        }
+    }
+}
+
+impl<M: StateMachine> Protocol for Raft<M> {
+    type Machine = M;
+    type Request = RequestOf<M>;
+    type Reply = Reply;
+
+    fn open(
+        id: String,
+        peers: Vec<String>,
+        machine: M,
+        dir: Option<&Path>,
+        now: Instant,
+    ) -> io::Result<Self> {
+        let storage: Box<dyn Storage<M::Command, M::Snapshot>> = match dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let log = FileStore::new(dir.join("log"))?;
+                Box::new(Persister::new(log, dir.to_path_buf()))
+            }
+            None => Box::new(Volatile),
+        };
+        Raft::new(id, peers, machine, storage, now)
+    }
+
+    /// tick does whatever is due by `now`: a leader's heartbeat or a follower's election.
+    fn tick(&mut self, now: Instant) -> Outgoing<RequestOf<M>> {
+        if self.is_leader() {
+            if now < self.next_heartbeat {
+                return vec![];
+            }
+            self.next_heartbeat = now + HEARTBEAT_INTERVAL;
+            return self.replicate();
+        }
+        if now < self.election_deadline {
+            return vec![];
+        }
+        self.start_election(now)
+    }
+
+    /// propose appends `command` to the log if this node is the leader. What it returns once
+    /// applied is sent on the returned channel.
+    fn propose(&mut self, command: M::Command) -> Result<Applied<M::Output>, NotLeader> {
+        if !self.is_leader() {
+            return Err(NotLeader {
+                leader: self.leader.clone(),
+            });
+        }
+        self.append_log(
+            self.last_index() + 1,
+            vec![Entry {
+                term: self.term,
+                command: Some(command),
+            }],
+        );
+        let (tx, rx) = oneshot::channel();
+        self.pending.insert(self.last_index(), (self.term, tx));
+        // Without peers there's nobody to wait on.
+        self.advance_commit();
+        Ok(rx)
+    }
+
+    /// handle answers a request from another node.
+    fn handle(&mut self, req: &RequestOf<M>, now: Instant) -> Reply {
+        match req {
+            Request::RequestVote(v) => Reply::Vote(self.on_request_vote(v, now)),
+            Request::AppendEntries(a) => Reply::Append(self.on_append_entries(a, now)),
+            Request::InstallSnapshot(s) => Reply::Snapshot(self.on_install_snapshot(s, now)),
+        }
+    }
+
+    /// handle_reply takes in a peer's reply to one of our requests.
+    fn handle_reply(&mut self, from: &str, reply: Reply, now: Instant) -> Outgoing<RequestOf<M>> {
+        if reply.term() > self.term {
+            self.observe(reply.term());
+            return vec![];
+        }
+        if reply.term() < self.term {
+            return vec![];
+        }
+
+        match (&mut self.role, reply) {
+            (Role::Candidate { votes }, Reply::Vote(r)) => {
+                if r.granted {
+                    votes.insert(from.to_string());
+                }
+                if votes.len() >= self.quorum() {
+                    return self.become_leader(now);
+                }
+                vec![]
+            }
+            (
+                Role::Leader {
+                    next_index,
+                    match_index,
+                },
+                Reply::Append(r),
+            ) => {
+                let (Some(next), Some(matched)) =
+                    (next_index.get_mut(from), match_index.get_mut(from))
+                else {
+                    return vec![];
+                };
+                if r.success {
+                    *matched = (*matched).max(r.last_index);
+                    *next = *matched + 1;
+                    self.advance_commit();
+                    vec![]
+                } else {
+                    // Back up and try again straight away rather than wait for the heartbeat.
+                    *next = (*next - 1).min(r.last_index + 1).max(1);
+                    let next = *next;
+                    vec![(from.to_string(), self.append_to(next))]
+                }
+            }
+            (
+                Role::Leader {
+                    next_index,
+                    match_index,
+                },
+                Reply::Snapshot(r),
+            ) => {
+                let (Some(next), Some(matched)) =
+                    (next_index.get_mut(from), match_index.get_mut(from))
+                else {
+                    return vec![];
+                };
+                *matched = (*matched).max(r.last_index);
+                *next = *matched + 1;
+                self.advance_commit();
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn reply_type(req: &RequestOf<M>) -> &'static str {
+        req.reply_type()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Log is a state machine that remembers every command and returns how many there have been.
+    #[derive(Debug, Default)]
+    struct Log(Vec<u64>);
+
+    impl StateMachine for Log {
+        type Command = u64;
+        type Output = usize;
+        type Snapshot = Vec<u64>;
+
+        fn apply(&mut self, command: &u64) -> usize {
+            self.0.push(*command);
+            self.0.len()
+        }
+
+        fn snapshot(&self) -> Vec<u64> {
+            self.0.clone()
+        }
+
+        fn restore(&mut self, snapshot: Vec<u64>) {
+            self.0 = snapshot;
+        }
+    }
+
+    fn raft(
+        id: &str,
+        peers: Vec<String>,
+        storage: Box<dyn Storage<u64, Vec<u64>>>,
+        now: Instant,
+    ) -> Raft<Log> {
+        Raft::new(id.to_string(), peers, Log::default(), storage, now).expect("failed to start")
+    }
+
+    // Cluster delivers messages between Raft nodes straight away, except to and from the nodes
+    // that are cut off.
+    struct Cluster {
+        nodes: HashMap<String, Raft<Log>>,
+        cut: HashSet<String>,
+        now: Instant,
+    }
+
+    impl Cluster {
+        fn new(n: usize) -> Self {
+            let ids: Vec<String> = (1..=n).map(|i| format!("n{}", i)).collect();
+            let now = Instant::now();
+            let nodes = ids
+                .iter()
+                .map(|id| {
+                    let peers = ids.iter().filter(|p| *p != id).cloned().collect();
+                    (id.clone(), raft(id, peers, Box::new(Volatile), now))
+                })
+                .collect();
+            Self {
+                nodes,
+                cut: HashSet::new(),
+                now,
+            }
+        }
+
+        fn node(&mut self, id: &str) -> &mut Raft<Log> {
+            self.nodes.get_mut(id).unwrap()
+        }
+
+        // deliver sends `out` from `from` and everything that follows from it.
+        fn deliver(&mut self, from: &str, out: Outgoing<RequestOf<Log>>) {
+            let mut queue: VecDeque<(String, String, RequestOf<Log>)> = out
+                .into_iter()
+                .map(|(to, req)| (from.to_string(), to, req))
+                .collect();
+            while let Some((from, to, req)) = queue.pop_front() {
+                if self.cut.contains(&from) || self.cut.contains(&to) {
+                    continue;
+                }
+                let now = self.now;
+                let reply = self.node(&to).handle(&req, now);
+                let out = self.node(&from).handle_reply(&to, reply, now);
+                queue.extend(out.into_iter().map(|(t, r)| (from.clone(), t, r)));
+            }
+        }
+
+        // elect makes `id` stand for election.
+        fn elect(&mut self, id: &str) {
+            let now = self.now;
+            let out = self.node(id).start_election(now);
+            self.deliver(id, out);
+        }
+
+        // heartbeat has `id` replicate its log, if it's the leader.
+        fn heartbeat(&mut self, id: &str) {
+            let out = self.node(id).replicate();
+            self.deliver(id, out);
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let mut raft = raft("n1", vec![], Box::new(Volatile), Instant::now());
+        assert!(raft.is_leader());
+        let mut applied = raft.propose(7).expect("not the leader");
+        assert_eq!(applied.try_recv().unwrap().unwrap(), 1);
+        assert_eq!(raft.machine().0, vec![7]);
+    }
+
+    #[test]
+    fn election() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+        assert!(c.node("n1").is_leader());
+        assert_eq!(c.node("n1").term(), 1);
+        assert!(matches!(
+            c.node("n2").propose(1),
+            Err(NotLeader { leader: Some(l) }) if l == "n1"
+        ));
+
+        // n2 can't win without a log as up to date as n1's, even in a later term.
+        let now = c.now;
+        c.node("n1").propose(1).unwrap();
+        c.heartbeat("n1");
+        c.cut.insert("n1".to_string());
+        c.node("n3").log.clear();
+        c.elect("n3");
+        assert!(!c.node("n3").is_leader());
+        c.elect("n2");
+        assert!(c.node("n2").is_leader());
+        assert_eq!(c.node("n2").term(), 3);
+        assert!(c.node("n2").tick(now).is_empty());
+    }
+
+    #[test]
+    fn replication() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+        let mut applied: Vec<_> = (10..13).map(|i| c.node("n1").propose(i).unwrap()).collect();
+        assert!(applied[0].try_recv().is_err());
+
+        // Once a majority has them they're committed and applied on the leader, and the
+        // followers catch up on the next heartbeat.
+        c.cut.insert("n3".to_string());
+        c.heartbeat("n1");
+        for (i, a) in applied.iter_mut().enumerate() {
+            assert_eq!(a.try_recv().unwrap().unwrap(), i + 1);
+        }
+        c.heartbeat("n1");
+        assert_eq!(c.node("n2").machine().0, vec![10, 11, 12]);
+        assert!(c.node("n3").machine().0.is_empty());
+
+        c.cut.clear();
+        c.heartbeat("n1");
+        c.heartbeat("n1");
+        assert_eq!(c.node("n3").machine().0, vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn lost_leadership() {
+        let mut c = Cluster::new(3);
+        c.elect("n1");
+        c.heartbeat("n1");
+
+        // n1 takes a command while it's cut off, so it's never committed.
+        c.cut.insert("n1".to_string());
+        let mut lost = c.node("n1").propose(1).unwrap();
+        c.heartbeat("n1");
+        c.elect("n2");
+        let mut kept = c.node("n2").propose(2).unwrap();
+        c.heartbeat("n2");
+        assert_eq!(kept.try_recv().unwrap().unwrap(), 1);
+
+        // Once it's back n1 follows n2, and its own entry is replaced by n2's.
+        c.cut.clear();
+        c.heartbeat("n2");
+        assert!(!c.node("n1").is_leader());
+        assert_eq!(c.node("n1").machine().0, vec![2]);
+        let e = lost.try_recv().unwrap().unwrap_err();
+        assert_eq!(e.code, ErrorCode::TemporarilyUnavailable);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut c = Cluster::new(3);
+        for raft in c.nodes.values_mut() {
+            raft.snapshot_after = 4;
+        }
+        c.elect("n1");
+        // A heartbeat from before any of the commands that only turns up once they're snapshotted.
+        let stale = c.node("n1").append_to(2);
+        c.cut.insert("n3".to_string());
+        for i in 1..=10 {
+            c.node("n1").propose(i).unwrap();
+            c.heartbeat("n1");
+        }
+        c.heartbeat("n1");
+
+        // The no-op and 10 entries were applied, so the last snapshot is up to 8.
+        let n1 = c.node("n1");
+        assert_eq!(n1.snapshot_index(), 8);
+        assert_eq!(n1.log.len(), 3);
+        assert_eq!(c.node("n2").snapshot_index(), 8);
+        let now = c.now;
+        let Reply::Append(reply) = c.node("n2").handle(&stale, now) else {
+            panic!("expected an append reply");
+        };
+        assert!(reply.success);
+        assert_eq!(reply.last_index, 8);
+
+        // n3 has to be caught up from the snapshot, since the entries it's missing are gone.
+        c.cut.clear();
+        c.heartbeat("n1");
+        c.heartbeat("n1");
+        let n3 = c.node("n3");
+        assert_eq!(n3.snapshot_index(), 8);
+        assert_eq!(n3.machine().0, (1..=10).collect::<Vec<_>>());
+        c.node("n1").propose(11).unwrap();
+        c.heartbeat("n1");
+        c.heartbeat("n1");
+        assert_eq!(c.node("n3").machine().0, (1..=11).collect::<Vec<_>>());
+    }
+
+    // Full is storage that's run out of space.
+    struct Full;
+
+    impl Storage<u64, Vec<u64>> for Full {
+        fn load(&mut self) -> io::Result<Saved<u64, Vec<u64>>> {
+            Volatile.load()
+        }
+
+        fn save_state(&mut self, _: &HardState) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::StorageFull))
+        }
+
+        fn append(&mut self, _: u64, _: &[Entry<u64>]) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::StorageFull))
+        }
+
+        fn save_snapshot(&mut self, _: &Snapshot<Vec<u64>>, _: &[Entry<u64>]) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::StorageFull))
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to persist raft state")]
+    fn persist_failure() {
+        let mut raft = raft("n1", vec![], Box::new(Full), Instant::now());
+        let _ = raft.propose(1);
+    }
+
+    #[test]
+    fn restart() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let storage = || {
+            let log = crate::store::FileStore::new(dir.path().join("log")).unwrap();
+            Box::new(Persister::new(log, dir.path().to_path_buf()))
+        };
+
+        let mut n1 = raft("n1", vec![], storage(), Instant::now()).with_snapshot_after(3);
+        for i in 1..=4 {
+            n1.propose(i).unwrap();
+        }
+        assert_eq!(n1.snapshot_index(), 3);
+        drop(n1);
+
+        // The snapshot is restored, the rest of the log is applied again, and it's in a later
+        // term.
+        let n1 = raft("n1", vec![], storage(), Instant::now());
+        assert_eq!(n1.machine().0, vec![1, 2, 3, 4]);
+        assert_eq!(n1.term(), 2);
+        assert_eq!(n1.last_index(), 6);
+    }
+}
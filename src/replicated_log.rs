@@ -1,9 +1,14 @@
+use crate::kv::{self, Kv};
+use crate::payload::{ErrorBody, Message, Payload, ResponseBody};
 use crate::{config, node, store};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
-use std::io::{BufRead, Write};
-use tracing::info;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info};
 
 // Goals(s):
 // https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-kafka
@@ -17,216 +22,1063 @@ use tracing::info;
 // Maelstrom is checking for:
 // - Lost writes: for example, a client sees offset 10 but not offset 5.
 // - Monotonic increasing offsets: an offset for a log should always be increasing.
+//
+// How the logs are kept is picked at startup:
+//
+// - `local` keeps them in a `store::SegmentedLog` on the node, which is only correct with a
+//   single node.
+// - `kv` keeps everything in Maelstrom's key/value services so that any node can serve any
+//   request. A log's next offset is allocated with a compare-and-swap on `lin-kv` and the message
+//   is then created in `seq-kv` under that offset. A writer can die in between, so a reader that
+//   finds an allocated offset empty fills it with a tombstone and moves on, and a writer that finds
+//   its offset tombstoned allocates another one. Committed offsets are kept in `lin-kv` too.
+// - `leader` gives every key an owner by hashing it over the nodes from `init`. The owner keeps
+//   the key's log on disk, the same as in `local` mode, and every other node forwards requests
+//   for the key to it. Nothing but the nodes themselves is involved.
+//
+// Logs kept on disk are compacted in the background every so often. Once a key has a committed
+// offset nothing before it is polled for again, so the segments entirely below it are removed.
+// The `store::Retention` the log is created with may remove more than that, e.g., to cap how much
+// disk a long run uses, at the cost of consumers that haven't caught up losing messages.
 
-// Generic payload wrapper for all message types
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(rename_all = "lowercase")]
-struct Payload<T> {
-    src: String,
-    dest: String,
-    body: T,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Mode {
+    Local,
+    #[default]
+    Kv,
+    Leader,
 }
 
-// Generic request body with common fields
-#[derive(Serialize, Deserialize, Debug)]
-struct RequestBody<T> {
-    #[serde(rename = "type")]
-    typ: String,
-    msg_id: u32,
-    #[serde(flatten)]
-    data: T,
-}
+// FORWARD_TIMEOUT is how long to wait on a key's owner in `leader` mode.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
 
-// Generic response body with common fields
-#[derive(Serialize, Deserialize, Debug)]
-struct ResponseBody<T> {
-    #[serde(rename = "type")]
-    typ: String,
-    in_reply_to: u32,
-    #[serde(flatten)]
-    data: T,
-}
-
-// Send-specific data structures
-#[derive(Serialize, Deserialize, Debug)]
-struct TopologyData {
+// Topology-specific data structures
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopologyData {
     topology: HashMap<String, Vec<String>>,
 }
 
-// Type aliases for cleaner usage
-type TopologyPayload = Payload<RequestBody<TopologyData>>;
-
 // Send-specific data structures
-#[derive(Serialize, Deserialize, Debug)]
-struct SendData {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendData {
     key: String,
-    msg: String,
+    msg: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SendResponseData {
-    offset: HashMap<String, u32>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SendResponseData {
+    offset: u64,
 }
 
-// Type aliases for cleaner usage
-type SendPayload = Payload<RequestBody<SendData>>;
-type SendResp = Payload<ResponseBody<SendResponseData>>;
-
 // Poll-specific data structures
-#[derive(Serialize, Deserialize, Debug)]
-struct PollData {
-    offsets: HashMap<String, u32>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PollData {
+    offsets: HashMap<String, u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct PollResponseData {
-    msgs: HashMap<String, Queue>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PollResponseData {
+    // The messages at or after the requested offset as `[offset, msg]` pairs, up to the
+    // `PollLimits`.
+    msgs: HashMap<String, Vec<(u64, u64)>>,
 }
 
-type PollPayload = Payload<RequestBody<PollData>>;
-type PollResp = Payload<ResponseBody<PollResponseData>>;
-
-#[derive(Serialize, Deserialize, Debug)]
-struct Queue {
-    offset: u32,
-    len: u32,
+/// PollLimits caps how much a single `poll_ok` carries. A poll may return any prefix of the
+/// messages after each offset, so a client that's cut short polls again from where it got to.
+#[derive(Debug, Clone, Copy)]
+pub struct PollLimits {
+    /// max_messages is the most messages returned for any one key.
+    pub max_messages: usize,
+    /// max_bytes is the most bytes the messages of the whole reply take up as JSON. The first
+    /// message is returned regardless, so a client always gets somewhere.
+    pub max_bytes: usize,
 }
 
-// Commit-specific data structures
-#[derive(Serialize, Deserialize, Debug)]
-struct CommitData {
-    offsets: HashMap<String, u32>,
+impl Default for PollLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_bytes: 64 * 1024,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct EmptyData {}
+impl PollLimits {
+    // read_limit is how many messages to read for a key, one more than is returned so that
+    // `apply` can tell when it's cut short.
+    fn read_limit(&self) -> usize {
+        self.max_messages.saturating_add(1)
+    }
+
+    // apply cuts `data` down to the limits, keeping a prefix of every key's messages.
+    fn apply(&self, data: &mut PollResponseData) {
+        let before: usize = data.msgs.values().map(Vec::len).sum();
+        for msgs in data.msgs.values_mut() {
+            msgs.truncate(self.max_messages);
+        }
+
+        // Messages are taken a round at a time, one from every key, so that a single busy
+        // key can't crowd the others out.
+        let mut keys: Vec<&String> = data.msgs.keys().collect();
+        keys.sort();
+        let mut keep = vec![0; keys.len()];
+        let (mut bytes, mut kept) = (0, 0);
+        'fill: for round in 0.. {
+            let mut more = false;
+            for (i, key) in keys.iter().enumerate() {
+                let Some((offset, msg)) = data.msgs[*key].get(round) else {
+                    continue;
+                };
+                more = true;
+                // `[offset,msg],`
+                bytes += offset.to_string().len() + msg.to_string().len() + 4;
+                if bytes > self.max_bytes && kept > 0 {
+                    break 'fill;
+                }
+                keep[i] += 1;
+                kept += 1;
+            }
+            if !more {
+                break;
+            }
+        }
+
+        let keep: HashMap<String, usize> = keys.into_iter().cloned().zip(keep).collect();
+        for (key, msgs) in &mut data.msgs {
+            msgs.truncate(keep[key]);
+        }
+
+        let after: usize = data.msgs.values().map(Vec::len).sum();
+        if after < before {
+            info!(
+                "truncated poll_ok from {} to {} messages to stay within {:?}",
+                before, after, self
+            );
+        }
+    }
+}
 
-type CommitPayload = Payload<RequestBody<CommitData>>;
-type CommitResp = Payload<ResponseBody<EmptyData>>;
+// Commit-specific data structures
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitData {
+    offsets: HashMap<String, u64>,
+}
 
 // ListCommitted-specific data structures
-#[derive(Serialize, Deserialize, Debug)]
-struct ListCommittedData {
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListCommittedData {
     keys: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ListCommittedResponseData {
-    offsets: HashMap<String, u32>,
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListCommittedResponseData {
+    offsets: HashMap<String, u64>,
 }
 
-type ListCommittedPayload = Payload<RequestBody<ListCommittedData>>;
-type ListCommittedOffsetsResp = Payload<ResponseBody<ListCommittedResponseData>>;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RequestBody {
+    Topology(TopologyData),
+    Send(SendData),
+    Poll(PollData),
+    CommitOffsets(CommitData),
+    ListCommittedOffsets(ListCommittedData),
+}
 
-// I use "untagged" in the following because the type tag differs based on the message.
-// I could split the Init message into a separate enum so that I could infer
-// the type based on different internal fields in the message body.
-#[derive(Debug, Serialize, Deserialize)]
+// The reply types that carry data. `topology_ok` and `commit_offsets_ok` don't have any.
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(untagged)]
-enum Message {
-    Topology(TopologyPayload),
-    Send(SendPayload),
-    Poll(PollPayload),
-    Commit(CommitPayload),
-    ListCommitted(ListCommittedPayload),
-    Other(HashMap<String, serde_json::Value>),
+pub enum ResponseData {
+    Send(SendResponseData),
+    Poll(PollResponseData),
+    ListCommitted(ListCommittedResponseData),
 }
 
-pub fn listen<R, W, T, S>(node: &mut node::Node<S, T>, reader: R, writer: &mut W) -> Result<()>
+/// ReplicatedLog serves the Kafka workload in whichever [`Mode`] it's created with.
+///
+/// A log's offsets start at 0 and have no gaps.
+#[derive(Debug)]
+pub struct ReplicatedLog {
+    mode: Mode,
+    // Only used in `local` and `leader` mode. Shared with the compaction task.
+    local: Arc<Mutex<LocalLog>>,
+    retention: store::Retention,
+    compaction_interval: Duration,
+    next_compaction: Option<SystemTime>,
+    poll_limits: PollLimits,
+}
+
+impl ReplicatedLog {
+    pub fn new(mode: Mode, log: store::SegmentedLog) -> Self {
+        Self {
+            mode,
+            local: Arc::new(Mutex::new(LocalLog {
+                log,
+                committed: HashMap::new(),
+            })),
+            retention: store::Retention::default(),
+            compaction_interval: Duration::from_secs(5),
+            next_compaction: None,
+            poll_limits: PollLimits::default(),
+        }
+    }
+
+    /// with_poll_limits sets how much a `poll_ok` may carry.
+    pub fn with_poll_limits(mut self, limits: PollLimits) -> Self {
+        self.poll_limits = limits;
+        self
+    }
+
+    /// with_retention sets how much of each log is kept on top of what hasn't been committed.
+    pub fn with_retention(mut self, retention: store::Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// with_compaction_interval sets how often the logs are compacted.
+    pub fn with_compaction_interval(mut self, interval: Duration) -> Self {
+        self.compaction_interval = interval;
+        self
+    }
+
+    // compact starts a compaction if one is due by `now`. It's done off the dispatch loop since
+    // it may have to remove a fair few files.
+    fn compact(&mut self, now: SystemTime) {
+        if self.next_compaction.is_some_and(|next| now < next) {
+            return;
+        }
+        self.next_compaction = Some(now + self.compaction_interval);
+
+        let local = self.local.clone();
+        let retention = self.retention.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut local = local.lock().expect("failed to take log lock");
+            match local.compact(&retention, now) {
+                Ok(0) => {}
+                Ok(n) => debug!("compaction removed {} segments", n),
+                Err(e) => error!("compaction failed: {:#}", e),
+            }
+        });
+    }
+}
+
+// LocalLog is the state kept on the node in `local` and `leader` mode.
+#[derive(Debug)]
+struct LocalLog {
+    log: store::SegmentedLog,
+    committed: HashMap<String, u64>,
+}
+
+impl LocalLog {
+    fn append(&mut self, key: &str, msg: u64, now: SystemTime) -> Result<u64> {
+        Ok(self.log.append(key, &msg.to_le_bytes(), now)?)
+    }
+
+    fn read_from(&self, key: &str, offset: u64, limit: usize) -> Result<Vec<(u64, u64)>> {
+        self.log
+            .read_from(key, offset, limit)?
+            .into_iter()
+            .map(|(offset, msg)| {
+                let msg = msg
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("message at {} of {} isn't a u64", offset, key))?;
+                Ok((offset, u64::from_le_bytes(msg)))
+            })
+            .collect()
+    }
+
+    // Committed offsets only move forward, a client that commits an older offset than another
+    // one already has doesn't take the log back.
+    fn commit(&mut self, key: &str, offset: u64) {
+        let c = self.committed.entry(key.to_string()).or_default();
+        *c = (*c).max(offset);
+    }
+
+    // compact removes what's below each key's committed offset and then whatever `retention`
+    // doesn't keep. It returns how many segments were removed.
+    fn compact(&mut self, retention: &store::Retention, now: SystemTime) -> Result<usize> {
+        let mut removed = 0;
+        for (key, &offset) in &self.committed {
+            removed += self.log.remove_before(key, offset)?;
+        }
+        removed += self.log.retain(retention, now)?;
+        Ok(removed)
+    }
+}
+
+impl<S, T> node::Handler<S, T> for ReplicatedLog
 where
-    R: BufRead,
-    W: Write,
     T: config::TimeSource,
     S: store::Store,
 {
-    // https://docs.rs/serde_json/latest/serde_json/fn.from_reader.html
-    // from_reader will read to end of deserialized object
-    let msg: Message = serde_json::from_reader(reader)?;
-    info!(">> input: {:?}", msg);
-    match msg {
-        Message::Send(SendPayload { src, dest, body }) => {
-            let resp = SendResp {
-                src: dest,
-                dest: src,
-                body: ResponseBody {
-                    typ: "send_ok".to_string(),
-                    in_reply_to: body.msg_id,
-                    data: SendResponseData {
-                        // TODO: appears this is supposed to be just an int with the offset
-                        // doesn't need to be keyed.
-                        //
-                        // Poll is when we need to remember the message key.
-                        offset: HashMap::new(),
-                    },
-                },
+    type Request = RequestBody;
+    type Response = ResponseBody<ResponseData>;
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        if self.mode == Mode::Kv && !matches!(msg.body.data, RequestBody::Topology(_)) {
+            // Waiting on the services can't happen on the dispatch loop.
+            let store = KvStore {
+                lin: node.kv(kv::Service::LinKv),
+                seq: node.kv(kv::Service::SeqKv),
+                poll_limits: self.poll_limits,
             };
+            let rpc = node.rpc.clone();
+            tokio::spawn(async move {
+                let sent = match store.handle(&msg.body.data).await {
+                    Ok((typ, data)) => rpc.send(msg.reply(typ, data)),
+                    Err(e) => rpc.send(msg.error(ErrorBody::from(e))),
+                };
+                if let Err(e) = sent {
+                    error!("failed to reply to {}: {:#}", msg.src, e);
+                }
+            });
+            return Ok(vec![]);
+        }
 
-            let mut resp_str = serde_json::to_string(&resp)?;
-            resp_str.push('\n');
-            info!("<< output: {:?}", &resp_str);
-            writer.write_all(resp_str.as_bytes())?;
-        }
-        Message::Poll(PollPayload { src, dest, body }) => {
-            panic!("not implemented");
-
-            let resp = PollResp {
-                src: dest,
-                dest: src,
-                body: ResponseBody {
-                    typ: "poll_ok".to_string(),
-                    in_reply_to: body.msg_id,
-                    data: PollResponseData {
-                        msgs: HashMap::new(),
+        let now = node.config.time_source.now();
+        self.compact(now);
+
+        if self.mode == Mode::Leader {
+            return Ok(self.handle_leader(node, msg, now)?.into_iter().collect());
+        }
+
+        let (typ, data) = self.handle_local(&msg.body.data, now)?;
+        Ok(vec![msg.reply(typ, data)])
+    }
+}
+
+impl ReplicatedLog {
+    fn handle_local(
+        &self,
+        req: &RequestBody,
+        now: SystemTime,
+    ) -> Result<(&'static str, Option<ResponseData>)> {
+        let mut local = self.local.lock().expect("failed to take log lock");
+        Ok(match req {
+            RequestBody::Topology(_) => ("topology_ok", None),
+            RequestBody::Send(data) => {
+                let offset = local.append(&data.key, data.msg, now)?;
+                (
+                    "send_ok",
+                    Some(ResponseData::Send(SendResponseData { offset })),
+                )
+            }
+            RequestBody::Poll(data) => {
+                let mut msgs = HashMap::new();
+                for (key, &offset) in &data.offsets {
+                    let limit = self.poll_limits.read_limit();
+                    msgs.insert(key.clone(), local.read_from(key, offset, limit)?);
+                }
+                let mut data = PollResponseData { msgs };
+                self.poll_limits.apply(&mut data);
+                ("poll_ok", Some(ResponseData::Poll(data)))
+            }
+            RequestBody::CommitOffsets(data) => {
+                for (key, &offset) in &data.offsets {
+                    local.commit(key, offset);
+                }
+                ("commit_offsets_ok", None)
+            }
+            RequestBody::ListCommittedOffsets(data) => {
+                // Keys that were never committed are left out.
+                let offsets = data
+                    .keys
+                    .iter()
+                    .filter_map(|key| Some((key.clone(), *local.committed.get(key)?)))
+                    .collect();
+                (
+                    "list_committed_offsets_ok",
+                    Some(ResponseData::ListCommitted(ListCommittedResponseData {
+                        offsets,
+                    })),
+                )
+            }
+        })
+    }
+
+    // handle_leader answers for the keys this node owns and forwards the rest to their owners.
+    // The reply is only returned directly if every key is owned here, otherwise it's sent once
+    // the owners have answered.
+    fn handle_leader<S, T>(
+        &self,
+        node: &node::Node<S, T>,
+        msg: Message<RequestBody>,
+        now: SystemTime,
+    ) -> Result<Option<Payload<ResponseBody<ResponseData>>>>
+    where
+        T: config::TimeSource,
+        S: store::Store,
+    {
+        let mut nodes: Vec<&str> = node.world.keys().map(String::as_str).collect();
+        nodes.push(&node.id);
+        nodes.sort();
+
+        let mut parts = split(&msg.body.data, |key| owner(&nodes, key));
+        let (typ, mut data) = match parts.remove(node.id.as_str()) {
+            Some(req) => self.handle_local(&req, now)?,
+            None => (reply_type(&msg.body.data), None),
+        };
+        if parts.is_empty() {
+            return Ok(Some(msg.reply(typ, data)));
+        }
+
+        // The calls are made here rather than in the task so that a reply can't arrive before
+        // the node knows to wait for it.
+        let calls: Vec<_> = parts
+            .into_iter()
+            .map(|(owner, req)| {
+                debug!("forwarding {:?} to {}", req, owner);
+                node.rpc.call::<_, Value>(
+                    Payload {
+                        src: node.id.clone(),
+                        dest: owner.to_string(),
+                        body: req,
                     },
-                },
+                    FORWARD_TIMEOUT,
+                )
+            })
+            .collect();
+        let rpc = node.rpc.clone();
+        let poll_limits = self.poll_limits;
+        tokio::spawn(async move {
+            let mut res = Ok(());
+            for call in calls {
+                match call.await {
+                    // Replies without data, e.g., `commit_offsets_ok`, don't need merging.
+                    Ok(reply) => {
+                        if let Ok(other) = serde_json::from_value::<ResponseData>(reply.body) {
+                            merge(&mut data, other);
+                        }
+                    }
+                    Err(e) => res = Err(ErrorBody::from(e)),
+                }
+            }
+            // Every owner kept to the limits, but together they may not have.
+            if let Some(ResponseData::Poll(data)) = &mut data {
+                poll_limits.apply(data);
+            }
+            let sent = match res {
+                Ok(()) => rpc.send(msg.reply(typ, data)),
+                Err(e) => rpc.send(msg.error(e)),
             };
+            if let Err(e) = sent {
+                error!("failed to reply to {}: {:#}", msg.src, e);
+            }
+        });
+        Ok(None)
+    }
+}
 
-            let mut resp_str = serde_json::to_string(&resp)?;
-            resp_str.push('\n');
-            info!("<< output: {:?}", &resp_str);
-            writer.write_all(resp_str.as_bytes())?;
-        }
-        Message::Commit(CommitPayload { src, dest, body }) => {
-            panic!("not implemented");
-
-            let resp = CommitResp {
-                src: dest,
-                dest: src,
-                body: ResponseBody {
-                    typ: "commit_offsets_ok".to_string(),
-                    in_reply_to: body.msg_id,
-                    data: EmptyData {},
-                },
-            };
+// owner picks the node that owns `key` out of every node in the cluster, sorted so that every
+// node picks the same one.
+fn owner<'a>(nodes: &[&'a str], key: &str) -> &'a str {
+    // FNV-1a, which unlike `DefaultHasher` is guaranteed to hash the same everywhere.
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    nodes[(hash % nodes.len() as u64) as usize]
+}
 
-            let mut resp_str = serde_json::to_string(&resp)?;
-            resp_str.push('\n');
-            info!("<< output: {:?}", &resp_str);
-            writer.write_all(resp_str.as_bytes())?;
-        }
-        Message::ListCommitted(ListCommittedPayload { src, dest, body }) => {
-            panic!("not implemented");
-
-            let resp = ListCommittedOffsetsResp {
-                src: dest,
-                dest: src,
-                body: ResponseBody {
-                    typ: "list_committed_offsets_ok".to_string(),
-                    in_reply_to: body.msg_id,
-                    data: ListCommittedResponseData {
+// split breaks a request up by the owner of each of the keys in it.
+fn split<'a>(req: &RequestBody, owner: impl Fn(&str) -> &'a str) -> HashMap<&'a str, RequestBody> {
+    let mut parts = HashMap::new();
+    match req {
+        RequestBody::Topology(_) => {}
+        RequestBody::Send(data) => {
+            parts.insert(owner(&data.key), req.clone());
+        }
+        RequestBody::Poll(data) => {
+            for (key, &offset) in &data.offsets {
+                let part = parts.entry(owner(key)).or_insert_with(|| {
+                    RequestBody::Poll(PollData {
                         offsets: HashMap::new(),
-                    },
-                },
+                    })
+                });
+                if let RequestBody::Poll(p) = part {
+                    p.offsets.insert(key.clone(), offset);
+                }
+            }
+        }
+        RequestBody::CommitOffsets(data) => {
+            for (key, &offset) in &data.offsets {
+                let part = parts.entry(owner(key)).or_insert_with(|| {
+                    RequestBody::CommitOffsets(CommitData {
+                        offsets: HashMap::new(),
+                    })
+                });
+                if let RequestBody::CommitOffsets(c) = part {
+                    c.offsets.insert(key.clone(), offset);
+                }
+            }
+        }
+        RequestBody::ListCommittedOffsets(data) => {
+            for key in &data.keys {
+                let part = parts.entry(owner(key)).or_insert_with(|| {
+                    RequestBody::ListCommittedOffsets(ListCommittedData { keys: vec![] })
+                });
+                if let RequestBody::ListCommittedOffsets(l) = part {
+                    l.keys.push(key.clone());
+                }
+            }
+        }
+    }
+    parts
+}
+
+fn reply_type(req: &RequestBody) -> &'static str {
+    match req {
+        RequestBody::Topology(_) => "topology_ok",
+        RequestBody::Send(_) => "send_ok",
+        RequestBody::Poll(_) => "poll_ok",
+        RequestBody::CommitOffsets(_) => "commit_offsets_ok",
+        RequestBody::ListCommittedOffsets(_) => "list_committed_offsets_ok",
+    }
+}
+
+// merge combines the replies for the parts of a request that was split up.
+fn merge(data: &mut Option<ResponseData>, other: ResponseData) {
+    match (data.as_mut(), other) {
+        (Some(ResponseData::Poll(d)), ResponseData::Poll(o)) => d.msgs.extend(o.msgs),
+        (Some(ResponseData::ListCommitted(d)), ResponseData::ListCommitted(o)) => {
+            d.offsets.extend(o.offsets)
+        }
+        (_, other) => *data = Some(other),
+    }
+}
+
+// KvStore keeps the logs in Maelstrom's key/value services.
+struct KvStore {
+    lin: Kv,
+    seq: Kv,
+    poll_limits: PollLimits,
+}
+
+impl KvStore {
+    async fn handle(
+        &self,
+        req: &RequestBody,
+    ) -> Result<(&'static str, Option<ResponseData>), kv::Error> {
+        match req {
+            RequestBody::Topology(_) => Ok(("topology_ok", None)),
+            RequestBody::Send(data) => {
+                let offset = self.append(&data.key, data.msg).await?;
+                Ok((
+                    "send_ok",
+                    Some(ResponseData::Send(SendResponseData { offset })),
+                ))
+            }
+            RequestBody::Poll(data) => {
+                let mut msgs = HashMap::new();
+                for (key, &offset) in &data.offsets {
+                    let limit = self.poll_limits.read_limit();
+                    msgs.insert(key.clone(), self.read_from(key, offset, limit).await?);
+                }
+                let mut data = PollResponseData { msgs };
+                self.poll_limits.apply(&mut data);
+                Ok(("poll_ok", Some(ResponseData::Poll(data))))
+            }
+            RequestBody::CommitOffsets(data) => {
+                for (key, &offset) in &data.offsets {
+                    self.commit(key, offset).await?;
+                }
+                Ok(("commit_offsets_ok", None))
+            }
+            RequestBody::ListCommittedOffsets(data) => {
+                let mut offsets = HashMap::new();
+                for key in &data.keys {
+                    match self.lin.read(&committed_key(key)).await {
+                        Ok(offset) => {
+                            offsets.insert(key.clone(), offset);
+                        }
+                        // Keys that were never committed are left out.
+                        Err(kv::Error::KeyDoesNotExist) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok((
+                    "list_committed_offsets_ok",
+                    Some(ResponseData::ListCommitted(ListCommittedResponseData {
+                        offsets,
+                    })),
+                ))
+            }
+        }
+    }
+
+    // append claims the log's next offset and then creates the message under it, starting over if
+    // a reader gave up on the offset first.
+    async fn append(&self, key: &str, msg: u64) -> Result<u64, kv::Error> {
+        loop {
+            let offset = self.claim(key).await?;
+            // Nothing else writes a message to a claimed offset, so `from` only matches a
+            // message that's already there if this is a retry of our own create.
+            let msg = Some(msg);
+            match self.seq.cas(&msg_key(key, offset), &msg, &msg, true).await {
+                Ok(()) => return Ok(offset),
+                Err(kv::Error::PreconditionFailed) => {
+                    debug!("offset {} of {} was given up on, retrying", offset, key)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn claim(&self, key: &str) -> Result<u64, kv::Error> {
+        loop {
+            let offset = self.next_offset(key).await?;
+            match self
+                .lin
+                .cas(&next_offset_key(key), &offset, &(offset + 1), true)
+                .await
+            {
+                Ok(()) => return Ok(offset),
+                Err(kv::Error::PreconditionFailed) => {
+                    debug!("offset {} of {} was taken, retrying", offset, key)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn next_offset(&self, key: &str) -> Result<u64, kv::Error> {
+        match self.lin.read(&next_offset_key(key)).await {
+            Ok(offset) => Ok(offset),
+            Err(kv::Error::KeyDoesNotExist) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // read_from reads the log up to its next offset. Nothing past it was claimed, so the offsets
+    // before it are all read at once rather than one round trip at a time.
+    async fn read_from(
+        &self,
+        key: &str,
+        offset: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, u64)>, kv::Error> {
+        let tail = self.next_offset(key).await?;
+        let mut msgs = Vec::new();
+        let mut start = offset;
+        // Offsets that were given up on don't count, so it takes another round to make up for
+        // them.
+        while msgs.len() < limit && start < tail {
+            let end = tail.min(start.saturating_add((limit - msgs.len()) as u64));
+            let mut reads = JoinSet::new();
+            for offset in start..end {
+                let seq = self.seq.clone();
+                let key = msg_key(key, offset);
+                reads.spawn(async move { (offset, read_msg(&seq, &key).await) });
+            }
+            let mut batch = Vec::new();
+            while let Some(read) = reads.join_next().await {
+                let (offset, msg) = read.expect("failed to join read");
+                if let Some(msg) = msg? {
+                    batch.push((offset, msg));
+                }
+            }
+            batch.sort();
+            msgs.extend(batch);
+            start = end;
+        }
+        Ok(msgs)
+    }
+
+    // commit only ever moves the committed offset forward, the same as in `local` mode.
+    async fn commit(&self, key: &str, offset: u64) -> Result<(), kv::Error> {
+        let committed = committed_key(key);
+        loop {
+            let current = match self.lin.read::<_, u64>(&committed).await {
+                Ok(current) if current >= offset => return Ok(()),
+                Ok(current) => Some(current),
+                Err(kv::Error::KeyDoesNotExist) => None,
+                Err(e) => return Err(e),
             };
+            let res = match current {
+                Some(current) => self.lin.cas(&committed, &current, &offset, false).await,
+                // Someone else may create it first, in which case the cas fails and we start over.
+                None => self.lin.cas(&committed, &offset, &offset, true).await,
+            };
+            match res {
+                Ok(()) => return Ok(()),
+                Err(kv::Error::PreconditionFailed | kv::Error::KeyDoesNotExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// read_msg reads the message at a claimed offset. One that has nothing in it yet is tombstoned, as
+// its writer may never get to it, unless the writer beats us to it.
+async fn read_msg(seq: &Kv, key: &str) -> Result<Option<u64>, kv::Error> {
+    match seq.read::<_, Option<u64>>(&key).await {
+        Ok(msg) => Ok(msg),
+        Err(kv::Error::KeyDoesNotExist) => {
+            match seq.cas(&key, &None::<u64>, &None::<u64>, true).await {
+                Ok(()) => Ok(None),
+                Err(kv::Error::PreconditionFailed) => seq.read(&key).await,
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn next_offset_key(key: &str) -> String {
+    format!("next-offset-{}", key)
+}
 
-            let mut resp_str = serde_json::to_string(&resp)?;
-            resp_str.push('\n');
-            info!("<< output: {:?}", &resp_str);
-            writer.write_all(resp_str.as_bytes())?;
+fn committed_key(key: &str) -> String {
+    format!("committed-{}", key)
+}
+
+fn msg_key(key: &str, offset: u64) -> String {
+    format!("msg-{}-{}", key, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::node::Sender;
+    use crate::rpc::Rpc;
+    use std::{io::Cursor, time};
+    use tokio::sync::mpsc;
+
+    fn log(dir: &tempfile::TempDir) -> store::SegmentedLog {
+        store::SegmentedLog::open(
+            dir.path().to_path_buf(),
+            store::SegmentedLogOptions::default(),
+        )
+        .expect("failed to open log")
+    }
+
+    #[tokio::test]
+    async fn replicated_log() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":123}}
+{"src":"c1","dest":"n1","body":{"type":"send","msg_id":3,"key":"k1","msg":456}}
+{"src":"c2","dest":"n1","body":{"type":"send","msg_id":1,"key":"k2","msg":789}}
+{"src":"c1","dest":"n1","body":{"type":"poll","msg_id":4,"offsets":{"k1":1,"k2":0,"k3":0}}}
+{"src":"c1","dest":"n1","body":{"type":"commit_offsets","msg_id":5,"offsets":{"k1":1}}}
+{"src":"c2","dest":"n1","body":{"type":"commit_offsets","msg_id":2,"offsets":{"k1":0}}}
+{"src":"c2","dest":"n1","body":{"type":"list_committed_offsets","msg_id":3,"keys":["k1","k2"]}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"send_ok","in_reply_to":2,"offset":0}}
+{"src":"n1","dest":"c1","body":{"type":"send_ok","in_reply_to":3,"offset":1}}
+{"src":"n1","dest":"c2","body":{"type":"send_ok","in_reply_to":1,"offset":0}}
+{"src":"n1","dest":"c1","body":{"type":"poll_ok","in_reply_to":4,"msgs":{"k1":[[1,456]],"k2":[[0,789]],"k3":[]}}}
+{"src":"n1","dest":"c1","body":{"type":"commit_offsets_ok","in_reply_to":5}}
+{"src":"n1","dest":"c2","body":{"type":"commit_offsets_ok","in_reply_to":2}}
+{"src":"n1","dest":"c2","body":{"type":"list_committed_offsets_ok","in_reply_to":3,"offsets":{"k1":1}}}
+"#;
+
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let mut actual: Vec<u8> = Vec::new();
+        n.run(
+            Cursor::new(input.as_bytes()),
+            &mut actual,
+            ReplicatedLog::new(Mode::Local, log(&dir)),
+        )
+        .await
+        .expect("run failed");
+
+        let actual = String::from_utf8(actual).unwrap();
+        for (a, e) in actual.lines().zip(expected.lines()) {
+            let a: serde_json::Value = serde_json::from_str(a).unwrap();
+            let e: serde_json::Value = serde_json::from_str(e).unwrap();
+            assert_json_diff::assert_json_eq!(a, e);
         }
-        Message::Other(m) => {
-            info!("other: {:?}", m);
+        assert_eq!(actual.lines().count(), expected.lines().count());
+    }
+
+    #[test]
+    fn poll_limits() {
+        let poll = || PollResponseData {
+            msgs: HashMap::from([
+                ("k1".to_string(), vec![(0, 10), (1, 11), (2, 12)]),
+                ("k2".to_string(), vec![(5, 20), (6, 21)]),
+                ("k3".to_string(), vec![]),
+            ]),
+        };
+        let limited = |limits: PollLimits| {
+            let mut data = poll();
+            limits.apply(&mut data);
+            serde_json::to_value(data.msgs).unwrap()
+        };
+
+        assert_eq!(
+            limited(PollLimits::default()),
+            serde_json::to_value(poll().msgs).unwrap()
+        );
+        assert_eq!(
+            limited(PollLimits {
+                max_messages: 1,
+                max_bytes: usize::MAX,
+            }),
+            serde_json::json!({"k1": [[0, 10]], "k2": [[5, 20]], "k3": []})
+        );
+        // `[0,10],` and `[5,20],` are 7 bytes each, so three messages fit in 21 bytes.
+        assert_eq!(
+            limited(PollLimits {
+                max_messages: usize::MAX,
+                max_bytes: 21,
+            }),
+            serde_json::json!({"k1": [[0, 10], [1, 11]], "k2": [[5, 20]], "k3": []})
+        );
+        // Even when nothing fits, the first message is returned.
+        assert_eq!(
+            limited(PollLimits {
+                max_messages: usize::MAX,
+                max_bytes: 1,
+            }),
+            serde_json::json!({"k1": [[0, 10]], "k2": [], "k3": []})
+        );
+
+        // The defaults are finite, so a long log is never returned whole.
+        let mut data = PollResponseData {
+            msgs: HashMap::from([("k1".to_string(), (0..5000).map(|i| (i, i)).collect())]),
+        };
+        PollLimits::default().apply(&mut data);
+        assert!(data.msgs["k1"].len() < 5000);
+    }
+
+    #[test]
+    fn compaction() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        // Every record gets a segment of its own.
+        let log = store::SegmentedLog::open(
+            dir.path().to_path_buf(),
+            store::SegmentedLogOptions {
+                max_segment_bytes: 1,
+                ..Default::default()
+            },
+        )
+        .expect("failed to open log");
+        let rl = ReplicatedLog::new(Mode::Local, log);
+        let now = time::UNIX_EPOCH + time::Duration::from_secs(1757680326);
+
+        for msg in 0..5 {
+            let send = RequestBody::Send(SendData {
+                key: "k1".to_string(),
+                msg,
+            });
+            rl.handle_local(&send, now).expect("send failed");
         }
+        let commit = RequestBody::CommitOffsets(CommitData {
+            offsets: HashMap::from([("k1".to_string(), 3)]),
+        });
+        rl.handle_local(&commit, now).expect("commit failed");
+
+        let mut local = rl.local.lock().unwrap();
+        let retention = store::Retention::default();
+        assert_eq!(local.compact(&retention, now).unwrap(), 3);
+        assert_eq!(
+            local.read_from("k1", 0, usize::MAX).unwrap(),
+            vec![(3, 3), (4, 4)]
+        );
+
+        // Retention goes past the committed offset, but the log still carries on.
+        let retention = store::Retention {
+            max_records: None,
+            max_age: Some(time::Duration::from_secs(1)),
+        };
+        let later = now + time::Duration::from_secs(10);
+        assert_eq!(local.compact(&retention, later).unwrap(), 1);
+        assert_eq!(local.read_from("k1", 0, usize::MAX).unwrap(), vec![(4, 4)]);
+        assert_eq!(local.append("k1", 5, later).unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn kv_store() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let service = kv::fake::serve(rpc.clone(), rx);
+        let store = |node: &str| KvStore {
+            lin: Kv::new(rpc.clone(), node.to_string(), kv::Service::LinKv),
+            seq: Kv::new(rpc.clone(), node.to_string(), kv::Service::SeqKv),
+            poll_limits: PollLimits::default(),
+        };
+        let (n1, n2) = (store("n1"), store("n2"));
+
+        // Both nodes append to the same log, every message gets its own offset.
+        let (a, b) = tokio::join!(n1.append("k1", 10), n2.append("k1", 20));
+        let mut offsets = vec![a.expect("append failed"), b.expect("append failed")];
+        offsets.sort();
+        assert_eq!(offsets, vec![0, 1]);
+        assert_eq!(n2.append("k1", 30).await.expect("append failed"), 2);
+
+        let msgs = n1.read_from("k1", 1, 10).await.expect("read failed");
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[1], (2, 30));
+        assert_eq!(
+            n1.read_from("k1", 1, 1).await.expect("read failed").len(),
+            1
+        );
+        assert!(
+            n1.read_from("k2", 0, 10)
+                .await
+                .expect("read failed")
+                .is_empty()
+        );
+
+        // Committed offsets never go backwards.
+        n1.commit("k1", 2).await.expect("commit failed");
+        n2.commit("k1", 1).await.expect("commit failed");
+        let list = ListCommittedData {
+            keys: vec!["k1".to_string(), "k2".to_string()],
+        };
+        let (typ, data) = n2
+            .handle(&RequestBody::ListCommittedOffsets(list))
+            .await
+            .expect("list failed");
+        assert_eq!(typ, "list_committed_offsets_ok");
+        assert_eq!(
+            serde_json::to_value(data).unwrap(),
+            serde_json::json!({"offsets": {"k1": 2}})
+        );
+
+        service.abort();
+    }
+
+    #[tokio::test]
+    async fn kv_store_failed_write() {
+        // The first message write fails after its offset was claimed, as if the node had died.
+        let (tx, mut rx) = mpsc::unbounded_channel::<Payload<serde_json::Value>>();
+        let (fake_tx, fake_rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+        let service = kv::fake::serve(rpc.clone(), fake_rx);
+        let failing = tokio::spawn({
+            let rpc = rpc.clone();
+            async move {
+                let mut failed = false;
+                while let Some(msg) = rx.recv().await {
+                    if !failed && msg.body["key"] == "msg-k1-0" {
+                        failed = true;
+                        rpc.resolve(Payload {
+                            src: msg.dest,
+                            dest: msg.src,
+                            body: serde_json::json!({
+                                "type": "error", "code": 11, "in_reply_to": msg.body["msg_id"]
+                            }),
+                        });
+                        continue;
+                    }
+                    fake_tx.send(msg).expect("fake service stopped");
+                }
+            }
+        });
+        let store = KvStore {
+            lin: Kv::new(rpc.clone(), "n1".to_string(), kv::Service::LinKv),
+            seq: Kv::new(rpc.clone(), "n1".to_string(), kv::Service::SeqKv),
+            poll_limits: PollLimits::default(),
+        };
+
+        assert!(store.append("k1", 10).await.is_err());
+        assert_eq!(store.append("k1", 20).await.expect("append failed"), 1);
+        assert_eq!(
+            store.read_from("k1", 0, 10).await.expect("read failed"),
+            vec![(1, 20)]
+        );
+
+        // The gap was tombstoned, so a late write to it can't slip in behind the reader.
+        let tombstoned = store
+            .seq
+            .cas(&msg_key("k1", 0), &Some(10), &Some(10), true)
+            .await;
+        assert!(matches!(tombstoned, Err(kv::Error::PreconditionFailed)));
+        assert_eq!(store.append("k1", 30).await.expect("append failed"), 2);
+        assert_eq!(
+            store.read_from("k1", 0, 10).await.expect("read failed"),
+            vec![(1, 20), (2, 30)]
+        );
+
+        failing.abort();
+        service.abort();
+    }
+
+    #[tokio::test]
+    async fn leader() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
+
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        let mine = keys
+            .iter()
+            .find(|k| owner(&["n1", "n2"], k) == "n1")
+            .unwrap();
+        let theirs = keys
+            .iter()
+            .find(|k| owner(&["n1", "n2"], k) == "n2")
+            .unwrap();
+
+        let (mut input, reader) = tokio::io::duplex(4096);
+        let (writer, output) = tokio::io::duplex(4096);
+        let mut output = BufReader::new(output).lines();
+
+        let driver = async {
+            let mut exchange = async |line: String| -> serde_json::Value {
+                input.write_all(line.as_bytes()).await.unwrap();
+                input.write_all(b"\n").await.unwrap();
+                let out = output.next_line().await.unwrap().expect("expected output");
+                serde_json::from_str(&out).unwrap()
+            };
+
+            exchange(r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#.to_string()).await;
+
+            let reply = exchange(format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":2,"key":"{mine}","msg":4}}}}"#)).await;
+            assert_eq!(reply["body"]["offset"], 0);
+
+            // Sends for someone else's key go to them and their answer is passed on.
+            let fwd = exchange(format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"send","msg_id":3,"key":"{theirs}","msg":5}}}}"#)).await;
+            assert_eq!(fwd["dest"], "n2");
+            assert_eq!(fwd["body"]["key"], theirs.as_str());
+            let reply = exchange(format!(r#"{{"src":"n2","dest":"n1","body":{{"type":"send_ok","in_reply_to":{},"offset":7}}}}"#, fwd["body"]["msg_id"])).await;
+            assert_eq!(reply["dest"], "c1");
+            assert_eq!(reply["body"]["in_reply_to"], 3);
+            assert_eq!(reply["body"]["offset"], 7);
+
+            // A poll that spans both gets the owners' answers merged.
+            let fwd = exchange(format!(r#"{{"src":"c1","dest":"n1","body":{{"type":"poll","msg_id":4,"offsets":{{"{mine}":0,"{theirs}":0}}}}}}"#)).await;
+            assert_eq!(
+                fwd["body"]["offsets"],
+                serde_json::json!({theirs.as_str(): 0})
+            );
+            let reply = exchange(format!(r#"{{"src":"n2","dest":"n1","body":{{"type":"poll_ok","in_reply_to":{},"msgs":{{"{theirs}":[[7,5]]}}}}}}"#, fwd["body"]["msg_id"])).await;
+            assert_eq!(
+                reply["body"]["msgs"],
+                serde_json::json!({mine.as_str(): [[0, 4]], theirs.as_str(): [[7, 5]]})
+            );
+
+            drop(input);
+        };
+
+        let (res, ()) = tokio::join!(
+            n.run(
+                BufReader::new(reader),
+                writer,
+                ReplicatedLog::new(Mode::Leader, log(&dir))
+            ),
+            driver
+        );
+        res.expect("run failed");
     }
-    Ok(())
 }
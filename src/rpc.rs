@@ -0,0 +1,287 @@
+use crate::node::Sender;
+use crate::payload::{ErrorBody, ErrorCode, Payload, RequestBody};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// Maelstrom only correlates a reply to a request by the `in_reply_to` field matching the
+// `msg_id` of the request. So every request we send gets a `msg_id` that's unique to this node
+// and we park a oneshot channel under that id until the matching reply shows up in the node's
+// dispatch loop.
+//
+// Handlers are synchronous, so a handler that needs to wait on a reply clones the `Rpc` into a
+// spawned task and replies to its own client from there once the call resolves.
+
+#[derive(Debug)]
+pub enum Error {
+    // No reply arrived before the deadline. The request may or may not have been applied.
+    Timeout,
+    // The node stopped dispatching before a reply arrived.
+    Closed,
+    // The peer (or service) replied with an `error`.
+    Reply(ErrorBody),
+    // A reply arrived but its body wasn't what the caller expected.
+    Decode(serde_json::Error),
+    Send(anyhow::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Timeout => write!(f, "timed out waiting for a reply"),
+            Error::Closed => write!(f, "node stopped before a reply arrived"),
+            Error::Reply(e) => write!(f, "{}", e),
+            Error::Decode(e) => write!(f, "failed to deserialize reply: {}", e),
+            Error::Send(e) => write!(f, "failed to send request: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// A request that timed out may or may not have been applied, so the client is told it's
+// indefinite. An error reply is passed on as it is.
+impl From<Error> for ErrorBody {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Reply(e) => e,
+            Error::Timeout => ErrorBody::new(ErrorCode::Timeout, e.to_string()),
+            e => ErrorBody::new(ErrorCode::Crash, e.to_string()),
+        }
+    }
+}
+
+type Pending = Arc<Mutex<HashMap<u32, oneshot::Sender<Payload<Value>>>>>;
+
+/// Rpc is a cloneable handle for sending messages from the node, including requests that expect
+/// a reply.
+#[derive(Debug, Clone)]
+pub struct Rpc {
+    tx: Sender,
+    msg_id: Arc<AtomicU32>,
+    pending: Pending,
+}
+
+impl Rpc {
+    pub fn new(tx: Sender) -> Self {
+        Self {
+            tx,
+            msg_id: Arc::new(AtomicU32::new(0)),
+            pending: Default::default(),
+        }
+    }
+
+    /// next_msg_id allocates a `msg_id` that hasn't been used by this node yet.
+    pub fn next_msg_id(&self) -> u32 {
+        self.msg_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// send writes a message without waiting for anything in return, e.g., a reply to a client.
+    pub fn send<B: Serialize>(&self, msg: Payload<B>) -> anyhow::Result<()> {
+        self.tx.send(msg)
+    }
+
+    /// call sends `msg` with a freshly allocated `msg_id` and returns a future that resolves
+    /// with the reply, or fails once `timeout` elapses.
+    ///
+    /// The request is sent before `call` returns, so the reply can't race the registration no
+    /// matter when the returned future is first polled.
+    pub fn call<B, R>(
+        &self,
+        msg: Payload<B>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Payload<R>, Error>> + Send + 'static
+    where
+        B: Serialize,
+        R: DeserializeOwned,
+    {
+        let msg_id = self.next_msg_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("failed to take pending lock")
+            .insert(msg_id, tx);
+        let sent = self.tx.send(msg.map(|data| RequestBody { msg_id, data }));
+        let pending = self.pending.clone();
+
+        async move {
+            let forget = || {
+                pending
+                    .lock()
+                    .expect("failed to take pending lock")
+                    .remove(&msg_id)
+            };
+
+            if let Err(e) = sent {
+                forget();
+                return Err(Error::Send(e));
+            }
+
+            let reply = match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(_)) => return Err(Error::Closed),
+                Err(_) => {
+                    forget();
+                    return Err(Error::Timeout);
+                }
+            };
+
+            if reply.body.get("type").and_then(Value::as_str) == Some("error") {
+                let e = serde_json::from_value(reply.body).map_err(Error::Decode)?;
+                return Err(Error::Reply(e));
+            }
+
+            let body = serde_json::from_value(reply.body).map_err(Error::Decode)?;
+            Ok(Payload {
+                src: reply.src,
+                dest: reply.dest,
+                body,
+            })
+        }
+    }
+
+    /// pending is the number of calls still waiting on a reply.
+    pub fn pending(&self) -> usize {
+        self.pending
+            .lock()
+            .expect("failed to take pending lock")
+            .len()
+    }
+
+    /// resolve completes the call that `msg` is a reply to. The message is handed back if it
+    /// isn't a reply to anything we're waiting on.
+    pub(crate) fn resolve(&self, msg: Payload<Value>) -> Option<Payload<Value>> {
+        let Some(id) = msg.body.get("in_reply_to").and_then(Value::as_u64) else {
+            return Some(msg);
+        };
+
+        let waiting = self
+            .pending
+            .lock()
+            .expect("failed to take pending lock")
+            .remove(&(id as u32));
+
+        match waiting {
+            // The caller may have given up in the meantime which is fine.
+            Some(tx) => {
+                let _ = tx.send(msg);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use tokio::sync::mpsc;
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "lowercase")]
+    enum Request {
+        Ping,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Pong {
+        #[serde(rename = "type")]
+        typ: String,
+        in_reply_to: u32,
+    }
+
+    fn ping() -> Payload<Request> {
+        Payload {
+            src: "n1".to_string(),
+            dest: "n2".to_string(),
+            body: Request::Ping,
+        }
+    }
+
+    #[tokio::test]
+    async fn call() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+
+        let first = rpc.call::<_, Pong>(ping(), Duration::from_secs(5));
+        let second = rpc.call::<_, Pong>(ping(), Duration::from_secs(5));
+        assert_eq!(rpc.pending(), 2);
+
+        let sent: Vec<Value> = vec![
+            rx.recv().await.expect("expected a request").body,
+            rx.recv().await.expect("expected a request").body,
+        ];
+        assert_eq!(
+            sent,
+            vec![
+                serde_json::json!({"msg_id": 1, "type": "ping"}),
+                serde_json::json!({"msg_id": 2, "type": "ping"}),
+            ]
+        );
+
+        // Replies can arrive in any order.
+        for id in [2, 1] {
+            let reply = Payload {
+                src: "n2".to_string(),
+                dest: "n1".to_string(),
+                body: serde_json::json!({"type": "pong", "in_reply_to": id}),
+            };
+            assert!(rpc.resolve(reply).is_none());
+        }
+
+        let first = first.await.expect("first call failed");
+        let second = second.await.expect("second call failed");
+        assert_eq!(first.src, "n2");
+        assert_eq!(first.body.in_reply_to, 1);
+        assert_eq!(second.body.in_reply_to, 2);
+        assert_eq!(rpc.pending(), 0);
+
+        // A reply nobody is waiting on is handed back.
+        let stray = Payload {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: serde_json::json!({"type": "pong", "in_reply_to": 99}),
+        };
+        assert!(rpc.resolve(stray).is_some());
+    }
+
+    #[tokio::test]
+    async fn error_reply() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+
+        let call = rpc.call::<_, Pong>(ping(), Duration::from_secs(5));
+        let reply = Payload {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: serde_json::json!({"type": "error", "in_reply_to": 1, "code": 20, "text": "not found"}),
+        };
+        assert!(rpc.resolve(reply).is_none());
+
+        match call.await {
+            Err(Error::Reply(e)) => {
+                assert_eq!(e, ErrorBody::new(ErrorCode::KeyDoesNotExist, "not found"))
+            }
+            res => panic!("expected an error reply, got {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let rpc = Rpc::new(Sender::new(tx));
+
+        let res = rpc.call::<_, Pong>(ping(), Duration::from_millis(10)).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        assert_eq!(rpc.pending(), 0);
+    }
+}
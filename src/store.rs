@@ -1,10 +1,21 @@
 use fs2::FileExt;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Error, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // std::io::{Read,Write} Supertrait
-pub trait Store: Write + Read + BufRead + Seek {}
+pub trait Store: Write + Read + BufRead + Seek {
+    /// sync makes whatever has been written durable, for stores where flushing alone doesn't.
+    fn sync(&mut self) -> Result<(), Error> {
+        self.flush()
+    }
+
+    /// replace swaps the whole contents of the store for `contents`, all at once, and starts
+    /// reading from the beginning again.
+    fn replace(&mut self, contents: &[u8]) -> Result<(), Error>;
+}
 
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
@@ -21,7 +32,13 @@ impl MemoryStore {
     }
 }
 
-impl Store for MemoryStore {}
+impl Store for MemoryStore {
+    fn replace(&mut self, contents: &[u8]) -> Result<(), Error> {
+        self.buf = contents.to_vec();
+        self.position = 0;
+        Ok(())
+    }
+}
 impl Write for MemoryStore {
     fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
         for v in buf {
@@ -89,9 +106,22 @@ pub struct FileStore {
     // A BufReader<R> performs large, infrequent reads on the underlying Read and maintains an in-memory buffer of the
     // results.
     inner: BufReader<File>, // Not Copy-safe.
+    path: PathBuf,
 }
 
-impl Store for FileStore {}
+impl Store for FileStore {
+    fn sync(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.file.sync_data()
+    }
+
+    fn replace(&mut self, contents: &[u8]) -> Result<(), Error> {
+        write_atomic(&self.path, contents)?;
+        // The old descriptors still point at the file that was replaced.
+        *self = FileStore::new(self.path.clone())?;
+        Ok(())
+    }
+}
 impl FileStore {
     pub fn new(path: PathBuf) -> Result<Self, std::io::Error> {
         // We have two separate file descriptors: one for writing
@@ -106,7 +136,11 @@ impl FileStore {
             .open(path.as_path())?;
 
         let inner = BufReader::new(r);
-        Ok(FileStore { file: w, inner })
+        Ok(FileStore {
+            file: w,
+            inner,
+            path,
+        })
     }
 }
 
@@ -168,3 +202,598 @@ impl Seek for FileStore {
         self.inner.seek(pos)
     }
 }
+
+/// write_atomic replaces the file at `path` with `contents` so that a crash leaves either the old
+/// or the new contents, never a mix. They're written to a temporary file that's synced and then
+/// renamed over `path`, and the directory is synced so that the rename is durable too.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    f.write_all(contents)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// SegmentedLog is an append-only log per key, kept on disk the way Kafka keeps a partition:
+//
+//   <dir>/<hex(key)>/<base offset>.log
+//
+// A key's log is split into segments, each named after the offset of its first record, and a new
+// segment is started once the current one grows past `max_segment_bytes`. Each record is
+//
+//   offset (u64 LE) | timestamp in ms since the epoch (u64 LE) | length (u32 LE) | message
+//
+// Only the last segment of a key is ever written to. Every `index_interval` records a segment
+// notes down the offset and file position of the record, so a read only has to scan forward from
+// the closest noted record rather than from the start of the segment. The index is only kept in
+// memory and is rebuilt from the segments when the log is opened.
+//
+// The log is trimmed a whole segment at a time, oldest first, and the last segment is always
+// kept so that the next offset is never lost. Removed segments are either deleted or, if there is
+// an `archive_dir`, moved there under the same layout.
+
+const RECORD_HEADER: u64 = 20;
+
+#[derive(Debug, Clone)]
+pub struct SegmentedLogOptions {
+    pub max_segment_bytes: u64,
+    pub index_interval: u64,
+    pub archive_dir: Option<PathBuf>,
+}
+
+impl Default for SegmentedLogOptions {
+    fn default() -> Self {
+        Self {
+            max_segment_bytes: 1024 * 1024,
+            index_interval: 64,
+            archive_dir: None,
+        }
+    }
+}
+
+/// Retention is how much of each log is kept around, regardless of whether it's still needed.
+/// Nothing is removed for a limit that isn't set.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// max_records is how many of the most recent records are kept.
+    pub max_records: Option<u64>,
+    /// max_age is how long a record is kept after it was appended.
+    pub max_age: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    base_offset: u64,
+    // The offset the next record appended to this segment gets.
+    next_offset: u64,
+    size: u64,
+    // (offset, position) of every `index_interval`th record.
+    index: Vec<(u64, u64)>,
+    // The timestamp of the newest record, in ms since the epoch.
+    max_timestamp: u64,
+}
+
+impl Segment {
+    fn path(dir: &std::path::Path, base_offset: u64) -> PathBuf {
+        dir.join(format!("{:020}.log", base_offset))
+    }
+
+    // open scans an existing segment to rebuild its index. A record that was only partly written
+    // when the process died is cut off, a record that can't be in the segment is an error.
+    fn open(path: PathBuf, base_offset: u64, index_interval: u64) -> Result<Self, Error> {
+        let mut r = BufReader::new(File::open(&path)?);
+        let len = r.get_ref().metadata()?.len();
+        let mut seg = Segment {
+            path,
+            base_offset,
+            next_offset: base_offset,
+            size: 0,
+            index: Vec::new(),
+            max_timestamp: 0,
+        };
+
+        while let Some((offset, timestamp, msg_len)) = read_header(&mut r)? {
+            if seg.size + RECORD_HEADER + msg_len as u64 > len {
+                break;
+            }
+            let Some(relative) = offset.checked_sub(base_offset) else {
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "record at offset {} is before the start of {:?}",
+                        offset, seg.path
+                    ),
+                ));
+            };
+            if relative.is_multiple_of(index_interval) {
+                seg.index.push((offset, seg.size));
+            }
+            r.seek_relative(msg_len as i64)?;
+            seg.size += RECORD_HEADER + msg_len as u64;
+            seg.next_offset = offset + 1;
+            seg.max_timestamp = seg.max_timestamp.max(timestamp);
+        }
+
+        if seg.size < len {
+            tracing::warn!("truncating torn record at the end of {:?}", seg.path);
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&seg.path)?
+                .set_len(seg.size)?;
+        }
+        Ok(seg)
+    }
+
+    // position is where to start scanning for `offset` from.
+    fn position(&self, offset: u64) -> u64 {
+        match self.index.partition_point(|&(o, _)| o <= offset) {
+            0 => 0,
+            i => self.index[i - 1].1,
+        }
+    }
+}
+
+// read_header reads a record's offset, timestamp and length.
+fn read_header<R: Read>(r: &mut R) -> Result<Option<(u64, u64, u32)>, Error> {
+    let mut header = [0u8; RECORD_HEADER as usize];
+    match r.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let offset = u64::from_le_bytes(header[..8].try_into().expect("header is 20 bytes"));
+    let timestamp = u64::from_le_bytes(header[8..16].try_into().expect("header is 20 bytes"));
+    let len = u32::from_le_bytes(header[16..].try_into().expect("header is 20 bytes"));
+    Ok(Some((offset, timestamp, len)))
+}
+
+fn millis(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+#[derive(Debug)]
+struct KeyLog {
+    dir: PathBuf,
+    // Ordered by base offset, the last one is the one being appended to.
+    segments: Vec<Segment>,
+    // Kept open for as long as the segment is the one being appended to.
+    active: Option<File>,
+}
+
+impl KeyLog {
+    fn next_offset(&self) -> u64 {
+        self.segments.last().map_or(0, |s| s.next_offset)
+    }
+
+    fn start_offset(&self) -> u64 {
+        self.segments.first().map_or(0, |s| s.base_offset)
+    }
+}
+
+/// SegmentedLog is an on-disk, append-only log per key with offsets that start at 0 and have no
+/// gaps.
+#[derive(Debug)]
+pub struct SegmentedLog {
+    dir: PathBuf,
+    options: SegmentedLogOptions,
+    logs: HashMap<String, KeyLog>,
+}
+
+impl SegmentedLog {
+    /// open opens the logs kept in `dir`, creating it if it doesn't exist yet.
+    pub fn open(dir: PathBuf, options: SegmentedLogOptions) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir)?;
+        let mut logs = HashMap::new();
+
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(key) = name
+                .to_str()
+                .and_then(|n| hex::decode(n).ok())
+                .and_then(|k| String::from_utf8(k).ok())
+            else {
+                tracing::warn!("skipping {:?} which isn't a log", entry.path());
+                continue;
+            };
+
+            let mut segments = Vec::new();
+            for seg in std::fs::read_dir(entry.path())? {
+                let path = seg?.path();
+                let base_offset = path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_suffix(".log"))
+                    .and_then(|n| n.parse().ok());
+                if let Some(base_offset) = base_offset {
+                    segments.push(Segment::open(path, base_offset, options.index_interval)?);
+                }
+            }
+            segments.sort_by_key(|s| s.base_offset);
+
+            logs.insert(
+                key,
+                KeyLog {
+                    dir: entry.path(),
+                    segments,
+                    active: None,
+                },
+            );
+        }
+
+        Ok(Self { dir, options, logs })
+    }
+
+    /// append adds `msg` to the end of `key`'s log and returns the offset it was given.
+    /// `timestamp` is when it was appended, which is what [`Retention::max_age`] goes by. The
+    /// key can't be empty.
+    pub fn append(&mut self, key: &str, msg: &[u8], timestamp: SystemTime) -> Result<u64, Error> {
+        if key.is_empty() {
+            return Err(Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "key can't be empty",
+            ));
+        }
+        let log = match self.logs.entry(key.to_string()) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let dir = self.dir.join(hex::encode(key));
+                std::fs::create_dir_all(&dir)?;
+                e.insert(KeyLog {
+                    dir,
+                    segments: Vec::new(),
+                    active: None,
+                })
+            }
+        };
+
+        let offset = log.next_offset();
+        let full = log
+            .segments
+            .last()
+            .is_none_or(|s| s.size > 0 && s.size >= self.options.max_segment_bytes);
+        if full {
+            let path = Segment::path(&log.dir, offset);
+            log.segments.push(Segment {
+                path,
+                base_offset: offset,
+                next_offset: offset,
+                size: 0,
+                index: Vec::new(),
+                max_timestamp: 0,
+            });
+            log.active = None;
+        }
+
+        let seg = log
+            .segments
+            .last_mut()
+            .expect("there is always a segment to append to");
+        let file = match &mut log.active {
+            Some(f) => f,
+            None => log.active.insert(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&seg.path)?,
+            ),
+        };
+
+        let len: u32 = msg
+            .len()
+            .try_into()
+            .map_err(|_| Error::new(std::io::ErrorKind::InvalidInput, "message is too large"))?;
+        let timestamp = millis(timestamp);
+        let mut record = Vec::with_capacity(RECORD_HEADER as usize + msg.len());
+        record.extend_from_slice(&offset.to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(msg);
+        file.write_all(&record)?;
+
+        if (offset - seg.base_offset).is_multiple_of(self.options.index_interval) {
+            seg.index.push((offset, seg.size));
+        }
+        seg.size += record.len() as u64;
+        seg.next_offset = offset + 1;
+        seg.max_timestamp = seg.max_timestamp.max(timestamp);
+        Ok(offset)
+    }
+
+    /// read_from returns up to `limit` messages of `key`'s log starting at `offset`, together with
+    /// their offsets. Messages that have been removed are skipped.
+    pub fn read_from(
+        &self,
+        key: &str,
+        offset: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+        let mut msgs = Vec::new();
+        let Some(log) = self.logs.get(key) else {
+            return Ok(msgs);
+        };
+
+        // The segment `offset` is in, or the first one after it if it's gone.
+        let first = log.segments.partition_point(|s| s.next_offset <= offset);
+        for seg in &log.segments[first..] {
+            if msgs.len() >= limit {
+                break;
+            }
+            let mut r = BufReader::new(File::open(&seg.path)?);
+            let mut pos = seg.position(offset);
+            r.seek(SeekFrom::Start(pos))?;
+
+            // Records past `size` may be mid-append.
+            while pos < seg.size && msgs.len() < limit {
+                let Some((o, _, len)) = read_header(&mut r)? else {
+                    break;
+                };
+                if o < offset {
+                    r.seek_relative(len as i64)?;
+                } else {
+                    let mut msg = vec![0u8; len as usize];
+                    r.read_exact(&mut msg)?;
+                    msgs.push((o, msg));
+                }
+                pos += RECORD_HEADER + len as u64;
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// next_offset is the offset the next message appended to `key` will get.
+    pub fn next_offset(&self, key: &str) -> u64 {
+        self.logs.get(key).map_or(0, KeyLog::next_offset)
+    }
+
+    /// start_offset is the oldest offset of `key` that hasn't been removed.
+    pub fn start_offset(&self, key: &str) -> u64 {
+        self.logs.get(key).map_or(0, KeyLog::start_offset)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.logs.keys().map(String::as_str)
+    }
+
+    /// remove_before removes every segment of `key` whose records are all below `offset` and
+    /// returns how many there were.
+    pub fn remove_before(&mut self, key: &str, offset: u64) -> Result<usize, Error> {
+        let Some(log) = self.logs.get_mut(key) else {
+            return Ok(0);
+        };
+        // The last segment stays, even if it's entirely below `offset`.
+        let n = log
+            .segments
+            .partition_point(|s| s.next_offset <= offset)
+            .min(log.segments.len().saturating_sub(1));
+
+        for seg in log.segments.drain(..n) {
+            match &self.options.archive_dir {
+                Some(archive) => {
+                    let dir = archive.join(hex::encode(key));
+                    std::fs::create_dir_all(&dir)?;
+                    let to = Segment::path(&dir, seg.base_offset);
+                    tracing::debug!("archiving {:?} to {:?}", seg.path, to);
+                    std::fs::rename(&seg.path, to)?;
+                }
+                None => {
+                    tracing::debug!("removing {:?}", seg.path);
+                    std::fs::remove_file(&seg.path)?;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    /// retain removes the segments that `retention` no longer keeps from every log, as of `now`,
+    /// and returns how many there were.
+    pub fn retain(&mut self, retention: &Retention, now: SystemTime) -> Result<usize, Error> {
+        let cutoff = retention
+            .max_age
+            .and_then(|age| now.checked_sub(age))
+            .map(millis);
+
+        let mut removed = 0;
+        let keys: Vec<String> = self.logs.keys().cloned().collect();
+        for key in keys {
+            let log = &self.logs[&key];
+            let mut offset = 0;
+            if let Some(max) = retention.max_records {
+                offset = log.next_offset().saturating_sub(max);
+            }
+            if let Some(cutoff) = cutoff {
+                // Everything before the first segment with a record that's new enough.
+                let i = log.segments.partition_point(|s| s.max_timestamp < cutoff);
+                if let Some(seg) = log.segments.get(i) {
+                    offset = offset.max(seg.base_offset);
+                } else {
+                    offset = log.next_offset();
+                }
+            }
+            removed += self.remove_before(&key, offset)?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> SegmentedLogOptions {
+        SegmentedLogOptions {
+            // Room for 4 records of 8 bytes.
+            max_segment_bytes: 4 * (RECORD_HEADER + 8),
+            index_interval: 2,
+            archive_dir: None,
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1757680326 + secs)
+    }
+
+    fn read(log: &SegmentedLog, key: &str, offset: u64, limit: usize) -> Vec<(u64, u64)> {
+        log.read_from(key, offset, limit)
+            .expect("read failed")
+            .into_iter()
+            .map(|(o, m)| (o, u64::from_le_bytes(m.try_into().unwrap())))
+            .collect()
+    }
+
+    #[test]
+    fn segmented_log() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+
+        for i in 0..10u64 {
+            assert_eq!(log.append("k1", &(i * 10).to_le_bytes(), at(0)).unwrap(), i);
+        }
+        assert_eq!(log.append("k/2", &7u64.to_le_bytes(), at(0)).unwrap(), 0);
+
+        assert_eq!(
+            std::fs::read_dir(dir.path().join(hex::encode("k1")))
+                .unwrap()
+                .count(),
+            3
+        );
+        assert_eq!(read(&log, "k1", 3, 3), vec![(3, 30), (4, 40), (5, 50)]);
+        assert_eq!(read(&log, "k1", 8, 10), vec![(8, 80), (9, 90)]);
+        assert!(read(&log, "k1", 10, 10).is_empty());
+        assert_eq!(read(&log, "k/2", 0, 10), vec![(0, 7)]);
+        assert!(read(&log, "k3", 0, 10).is_empty());
+
+        // Everything is still there once the log is opened again.
+        drop(log);
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        assert_eq!(log.next_offset("k1"), 10);
+        assert_eq!(read(&log, "k1", 0, 2), vec![(0, 0), (1, 10)]);
+        assert_eq!(log.append("k1", &100u64.to_le_bytes(), at(0)).unwrap(), 10);
+        assert_eq!(read(&log, "k1", 9, 10), vec![(9, 90), (10, 100)]);
+    }
+
+    #[test]
+    fn torn_record() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        log.append("k1", &1u64.to_le_bytes(), at(0)).unwrap();
+        log.append("k1", &2u64.to_le_bytes(), at(0)).unwrap();
+        drop(log);
+
+        // Half of a third record made it to disk.
+        let path = Segment::path(&dir.path().join(hex::encode("k1")), 0);
+        let mut f = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        f.write_all(&2u64.to_le_bytes()).unwrap();
+        f.write_all(&0u64.to_le_bytes()).unwrap();
+        f.write_all(&8u32.to_le_bytes()).unwrap();
+        f.write_all(&[3, 0]).unwrap();
+        drop(f);
+
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        assert_eq!(log.append("k1", &3u64.to_le_bytes(), at(0)).unwrap(), 2);
+        assert_eq!(read(&log, "k1", 0, 10), vec![(0, 1), (1, 2), (2, 3)]);
+    }
+
+    #[test]
+    fn corrupt_record() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        log.append("k1", &1u64.to_le_bytes(), at(0)).unwrap();
+        let err = log.append("", &1u64.to_le_bytes(), at(0)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        drop(log);
+
+        // A segment that claims to start after the records in it.
+        let key_dir = dir.path().join(hex::encode("k1"));
+        std::fs::rename(Segment::path(&key_dir, 0), Segment::path(&key_dir, 5)).unwrap();
+
+        let err = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn remove_before() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let archive = tempfile::tempdir().expect("failed to create tempdir");
+        let mut log = SegmentedLog::open(
+            dir.path().to_path_buf(),
+            SegmentedLogOptions {
+                archive_dir: Some(archive.path().to_path_buf()),
+                ..options()
+            },
+        )
+        .unwrap();
+        for i in 0..10u64 {
+            log.append("k1", &i.to_le_bytes(), at(0)).unwrap();
+        }
+
+        // Offsets 0-3 and 4-7 are in the first two segments, but 7 is still needed.
+        assert_eq!(log.remove_before("k1", 7).unwrap(), 1);
+        assert_eq!(log.start_offset("k1"), 4);
+        assert_eq!(read(&log, "k1", 0, 2), vec![(4, 4), (5, 5)]);
+        assert!(
+            archive
+                .path()
+                .join(hex::encode("k1"))
+                .join(format!("{:020}.log", 0))
+                .exists()
+        );
+
+        // The last segment is kept so the log carries on where it left off.
+        assert_eq!(log.remove_before("k1", 100).unwrap(), 1);
+        assert_eq!(log.append("k1", &10u64.to_le_bytes(), at(0)).unwrap(), 10);
+        drop(log);
+        let log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        assert_eq!(log.start_offset("k1"), 8);
+        assert_eq!(read(&log, "k1", 0, 10), vec![(8, 8), (9, 9), (10, 10)]);
+    }
+
+    #[test]
+    fn retain() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let mut log = SegmentedLog::open(dir.path().to_path_buf(), options()).unwrap();
+        for i in 0..12u64 {
+            log.append("k1", &i.to_le_bytes(), at(i)).unwrap();
+            log.append("k2", &i.to_le_bytes(), at(i)).unwrap();
+        }
+
+        let by_records = Retention {
+            max_records: Some(6),
+            max_age: None,
+        };
+        // Only k1's first segment is entirely outside the last 6 records.
+        log.remove_before("k2", 4).unwrap();
+        assert_eq!(log.retain(&by_records, at(12)).unwrap(), 1);
+        assert_eq!(log.start_offset("k1"), 4);
+        assert_eq!(log.start_offset("k2"), 4);
+
+        // Records 4-7 were appended more than 3s before 11s.
+        let by_age = Retention {
+            max_records: None,
+            max_age: Some(Duration::from_secs(3)),
+        };
+        assert_eq!(log.retain(&by_age, at(11)).unwrap(), 2);
+        assert_eq!(log.start_offset("k1"), 8);
+        assert_eq!(log.retain(&Retention::default(), at(100)).unwrap(), 0);
+    }
+
+    #[test]
+    fn write_atomic() {
+        let dir = tempfile::tempdir().expect("failed to create tempdir");
+        let path = dir.path().join("state.json");
+        super::write_atomic(&path, b"one").unwrap();
+        super::write_atomic(&path, b"two").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        // Nothing's left behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
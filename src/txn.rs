@@ -0,0 +1,350 @@
+use crate::payload::{Message, Payload, ResponseBody};
+use crate::{config, node, store};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::debug;
+
+// Goals(s):
+// https://github.com/jepsen-io/maelstrom/blob/main/doc/workloads.md#workload-txn-rw-register
+// - Run transactions of read and write micro-operations against integer registers.
+// - Totally available: every node answers every transaction on its own, without waiting on any
+//   other node, even while it's partitioned from the rest.
+//
+// A transaction runs start to finish on the dispatch loop, so nothing else on the node sees it
+// half done. What the rest of the cluster sees depends on the isolation level it's run at:
+//
+// - `read-uncommitted` applies every write as soon as it's run and replicates each one on its
+//   own, so another node may see a value the transaction went on to overwrite.
+// - `read-committed` buffers the writes until the transaction is done and then applies them all
+//   at once. Only the last value written to each key is kept and they're replicated together in
+//   a single message, so no other transaction ever sees one half done.
+//
+// Replicated writes go through the outbox, which keeps re-sending them until they're acked so
+// that partitions only delay them. Writes to the same key on different nodes are ordered by a
+// Lamport clock, with the node id breaking ties, so every node ends up with the same value once
+// it has heard of every write.
+
+// REPLICATE_TTL is how long a transaction's writes keep being re-sent to a node that hasn't
+// acked them.
+const REPLICATE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Isolation {
+    ReadUncommitted,
+    #[default]
+    ReadCommitted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// MicroOp is one step of a transaction, `["r", key, null]` or `["w", key, value]`. A read's
+/// value is filled in when it's run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MicroOp(pub Op, pub u64, pub Option<u64>);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxnData {
+    txn: Vec<MicroOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicateData {
+    // The Lamport clock and node of the transaction the writes are from.
+    clock: u64,
+    node: String,
+    // `[key, value]` pairs, in the order they were written.
+    writes: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum RequestBody {
+    Txn(TxnData),
+    Replicate(ReplicateData),
+}
+
+#[derive(Debug, Clone)]
+struct Register {
+    value: u64,
+    version: (u64, String),
+}
+
+/// Txn serves the `txn-rw-register` workload.
+#[derive(Debug, Default)]
+pub struct Txn {
+    isolation: Isolation,
+    clock: u64,
+    registers: HashMap<u64, Register>,
+}
+
+impl Txn {
+    pub fn new(isolation: Isolation) -> Self {
+        Self {
+            isolation,
+            ..Default::default()
+        }
+    }
+
+    // apply writes `writes` at `version`, except to keys that already have a later one.
+    fn apply(&mut self, version: (u64, &str), writes: &[(u64, u64)]) {
+        for &(key, value) in writes {
+            let newer = self
+                .registers
+                .get(&key)
+                .is_none_or(|r| (r.version.0, r.version.1.as_str()) < version);
+            if newer {
+                self.registers.insert(
+                    key,
+                    Register {
+                        value,
+                        version: (version.0, version.1.to_string()),
+                    },
+                );
+            }
+        }
+    }
+
+    // run executes `txn` as node `id`, filling in its reads, and returns the writes to replicate
+    // to the other nodes.
+    fn run(&mut self, id: &str, txn: &mut [MicroOp]) -> Vec<ReplicateData> {
+        let mut replicate = Vec::new();
+        // Only used for read committed: the last value written to each key, in the order the
+        // keys were first written.
+        let mut buffered: Vec<(u64, u64)> = Vec::new();
+
+        for MicroOp(op, key, value) in txn.iter_mut() {
+            match (op, *value) {
+                (Op::Read, _) => {
+                    // A transaction always sees its own writes.
+                    *value = buffered
+                        .iter()
+                        .find(|(k, _)| k == key)
+                        .map(|&(_, v)| v)
+                        .or_else(|| self.registers.get(key).map(|r| r.value));
+                }
+                (Op::Write, Some(v)) => match self.isolation {
+                    Isolation::ReadUncommitted => {
+                        // Every write gets a version of its own so that they're applied in order
+                        // wherever they end up.
+                        self.clock += 1;
+                        self.apply((self.clock, id), &[(*key, v)]);
+                        replicate.push(ReplicateData {
+                            clock: self.clock,
+                            node: id.to_string(),
+                            writes: vec![(*key, v)],
+                        });
+                    }
+                    Isolation::ReadCommitted => match buffered.iter_mut().find(|(k, _)| k == key) {
+                        Some(w) => w.1 = v,
+                        None => buffered.push((*key, v)),
+                    },
+                },
+                // Writing nothing leaves the register as it is.
+                (Op::Write, None) => {}
+            }
+        }
+
+        if !buffered.is_empty() {
+            self.clock += 1;
+            self.apply((self.clock, id), &buffered);
+            replicate.push(ReplicateData {
+                clock: self.clock,
+                node: id.to_string(),
+                writes: buffered,
+            });
+        }
+        replicate
+    }
+
+    // replicate applies writes another node replicated.
+    fn replicate(&mut self, data: &ReplicateData) {
+        debug!(
+            "applying {} writes from {} at {}",
+            data.writes.len(),
+            data.node,
+            data.clock
+        );
+        self.clock = self.clock.max(data.clock);
+        self.apply((data.clock, &data.node), &data.writes);
+    }
+}
+
+impl<S, T> node::Handler<S, T> for Txn
+where
+    T: config::TimeSource,
+    S: store::Store,
+{
+    type Request = RequestBody;
+    type Response = ResponseBody<TxnData>;
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        match &msg.body.data {
+            RequestBody::Txn(data) => {
+                let mut txn = data.txn.clone();
+                let expiration = node.config.time_source.now() + REPLICATE_TTL;
+                for data in self.run(&node.id, &mut txn) {
+                    for peer in node.world.keys() {
+                        node.outbox.send(
+                            Payload {
+                                src: node.id.clone(),
+                                dest: peer.clone(),
+                                body: RequestBody::Replicate(data.clone()),
+                            },
+                            expiration,
+                        );
+                    }
+                }
+                Ok(vec![msg.reply("txn_ok", Some(TxnData { txn }))])
+            }
+            RequestBody::Replicate(data) => {
+                self.replicate(data);
+                Ok(vec![msg.reply("replicate_ok", None)])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Cursor, time};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    fn node() -> node::Node<store::MemoryStore, config::MockTime> {
+        let s = store::MemoryStore::new(Vec::new()).expect("failed to create store");
+        let cfg = config::Config::<config::MockTime>::new(config::MockTime {
+            now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
+        })
+        .expect("failed to get config");
+        node::Node::new(s, cfg)
+    }
+
+    #[tokio::test]
+    async fn txn() {
+        let input = r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1"]}}
+{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[["r",1,null],["w",1,6],["r",1,null],["w",2,9]]}}
+{"src":"c2","dest":"n1","body":{"type":"txn","msg_id":1,"txn":[["r",2,null],["w",2,3],["r",3,null]]}}
+"#;
+        let expected = r#"{"src":"n1","dest":"c1","body":{"type":"init_ok","in_reply_to":1}}
+{"src":"n1","dest":"c1","body":{"type":"txn_ok","in_reply_to":2,"txn":[["r",1,null],["w",1,6],["r",1,6],["w",2,9]]}}
+{"src":"n1","dest":"c2","body":{"type":"txn_ok","in_reply_to":1,"txn":[["r",2,9],["w",2,3],["r",3,null]]}}
+"#;
+
+        let mut actual: Vec<u8> = Vec::new();
+        node()
+            .run(Cursor::new(input.as_bytes()), &mut actual, Txn::default())
+            .await
+            .expect("run failed");
+        assert_eq!(String::from_utf8(actual).unwrap(), expected);
+    }
+
+    // dirty_reads runs a transaction on n1 that writes a key twice while n2 reads it in between
+    // every write n1 replicates, and returns what n2 saw.
+    fn dirty_reads(isolation: Isolation) -> Vec<Option<u64>> {
+        let (mut n1, mut n2) = (Txn::new(isolation), Txn::new(isolation));
+        let mut seen = Vec::new();
+        let mut read = |n2: &mut Txn| {
+            let mut txn = [MicroOp(Op::Read, 1, None)];
+            assert!(n2.run("n2", &mut txn).is_empty());
+            seen.push(txn[0].2);
+        };
+
+        let mut txn = [
+            MicroOp(Op::Write, 1, Some(1)),
+            MicroOp(Op::Read, 1, None),
+            MicroOp(Op::Write, 1, Some(2)),
+        ];
+        read(&mut n2);
+        for data in n1.run("n1", &mut txn) {
+            n2.replicate(&data);
+            read(&mut n2);
+        }
+        assert_eq!(txn[1].2, Some(1));
+        seen
+    }
+
+    #[test]
+    fn no_dirty_reads() {
+        assert_eq!(dirty_reads(Isolation::ReadCommitted), vec![None, Some(2)]);
+        // Without read committed, n2 sees the write n1 went on to overwrite.
+        assert_eq!(
+            dirty_reads(Isolation::ReadUncommitted),
+            vec![None, Some(1), Some(2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn replicate() {
+        let (mut input, reader) = tokio::io::duplex(4096);
+        let (writer, output) = tokio::io::duplex(4096);
+        let mut output = BufReader::new(output).lines();
+
+        let driver = async {
+            let mut send = async |line: &str| {
+                input.write_all(line.as_bytes()).await.unwrap();
+                input.write_all(b"\n").await.unwrap();
+            };
+            let mut recv = async || -> serde_json::Value {
+                let out = output.next_line().await.unwrap().expect("expected output");
+                serde_json::from_str(&out).unwrap()
+            };
+
+            send(r#"{"src":"c1","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2"]}}"#).await;
+            recv().await;
+
+            // Only the last write to each key goes to n2, all in one message.
+            send(r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":2,"txn":[["w",1,6],["w",1,7],["r",2,null]]}}"#).await;
+            let (mut reply, mut repl) = (recv().await, recv().await);
+            if reply["dest"] == "n2" {
+                std::mem::swap(&mut reply, &mut repl);
+            }
+            assert_eq!(
+                reply["body"]["txn"],
+                serde_json::json!([["w", 1, 6], ["w", 1, 7], ["r", 2, null]])
+            );
+            assert_eq!(repl["body"]["type"], "replicate");
+            assert_eq!(repl["body"]["clock"], 1);
+            assert_eq!(repl["body"]["writes"], serde_json::json!([[1, 7]]));
+            send(&format!(
+                r#"{{"src":"n2","dest":"n1","body":{{"type":"replicate_ok","in_reply_to":{}}}}}"#,
+                repl["body"]["msg_id"]
+            ))
+            .await;
+
+            // n2 wrote key 1 concurrently and wins the tie on its id, but its write to key 2 is
+            // older than one n1 has since heard of.
+            send(r#"{"src":"n2","dest":"n1","body":{"type":"replicate","msg_id":1,"clock":5,"node":"n2","writes":[[2,1]]}}"#).await;
+            assert_eq!(recv().await["body"]["type"], "replicate_ok");
+            send(r#"{"src":"n2","dest":"n1","body":{"type":"replicate","msg_id":2,"clock":1,"node":"n2","writes":[[1,8],[2,2]]}}"#).await;
+            assert_eq!(recv().await["body"]["type"], "replicate_ok");
+
+            send(r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":3,"txn":[["r",1,null],["r",2,null]]}}"#).await;
+            assert_eq!(
+                recv().await["body"]["txn"],
+                serde_json::json!([["r", 1, 8], ["r", 2, 1]])
+            );
+
+            drop(input);
+        };
+
+        let mut n = node();
+        let (res, ()) = tokio::join!(
+            n.run(BufReader::new(reader), writer, Txn::default()),
+            driver
+        );
+        res.expect("run failed");
+    }
+}
@@ -1,73 +1,57 @@
-use crate::payload::{Payload, RequestBody, ResponseBody, UnhandledMessage};
+use crate::payload::{Message, Payload, ResponseBody};
 use crate::{config, node, store};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::io::{BufRead, Write};
-use tracing::info;
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Data {
+pub struct Data {
     #[serde(rename = "id")]
     unique_id: String,
 }
 
-type UniqueRequest = Payload<RequestBody<Data>>;
-type UniqueResponse = Payload<ResponseBody<Data>>;
-
-// I use "untagged" in the following because the type tag differs based on the message.
-// I could split the Init message into a separate enum so that I could infer
-// the type based on different internal fields in the message body.
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
-enum Message {
-    Unique(UniqueRequest),
-    Other(UnhandledMessage),
+pub enum RequestBody {
+    Generate,
 }
 
-pub fn listen<R, W, S, T>(node: &mut node::Node<S, T>, reader: R, writer: &mut W) -> Result<()>
+pub struct Unique;
+
+impl<S, T> node::Handler<S, T> for Unique
 where
-    R: BufRead,
-    W: Write,
     T: config::TimeSource,
     S: store::Store,
 {
-    // https://docs.rs/serde_json/latest/serde_json/fn.from_reader.html
-    // from_reader will read to end of deserialized object
-    let msg: Message = serde_json::from_reader(reader)?;
-    info!(">> input: {:?}", msg);
-    match msg {
-        Message::Unique(Payload { src, dest, body }) => {
-            let hash = Sha256::digest(
-                format!(
-                    "{}-{}-{:?}",
-                    dest,
-                    body.msg_id,
-                    node.config.time_source.now()
-                )
-                .into_bytes(),
-            );
-            io::to_writer(
-                writer,
-                &UniqueResponse {
-                    src: dest,
-                    dest: src,
-                    body: ResponseBody {
-                        typ: "generate_ok".to_string(),
-                        in_reply_to: body.msg_id,
-                        data: Some(Data {
-                            unique_id: hex::encode(hash),
-                        }),
-                    },
-                },
-            )?
-        }
-        Message::Other(m) => {
-            info!("other: {:?}", m);
+    type Request = RequestBody;
+    type Response = ResponseBody<Data>;
+
+    fn handle(
+        &mut self,
+        node: &mut node::Node<S, T>,
+        msg: Message<RequestBody>,
+    ) -> Result<Vec<Payload<Self::Response>>> {
+        match msg.body.data {
+            RequestBody::Generate => {
+                let hash = Sha256::digest(
+                    format!(
+                        "{}-{}-{:?}",
+                        msg.dest,
+                        msg.body.msg_id,
+                        node.config.time_source.now()
+                    )
+                    .into_bytes(),
+                );
+                Ok(vec![msg.reply(
+                    "generate_ok",
+                    Some(Data {
+                        unique_id: hex::encode(hash),
+                    }),
+                )])
+            }
         }
-    };
-    Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -76,8 +60,8 @@ mod tests {
     use config;
     use std::{io::Cursor, time};
 
-    #[test]
-    fn unique() {
+    #[tokio::test]
+    async fn unique() {
         let test_cases = vec![
             (
                 r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":1}}
@@ -94,20 +78,20 @@ mod tests {
         ];
 
         let buf: Vec<u8> = Vec::new();
-        let mut s = store::MemoryStore::new(buf).expect("failed to create store");
+        let s = store::MemoryStore::new(buf).expect("failed to create store");
         let cfg = config::Config::<config::MockTime>::new(config::MockTime {
             now: time::UNIX_EPOCH + time::Duration::from_secs(1757680326),
         })
         .expect("failed to get config");
-        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(&mut s, cfg);
+        let mut n: node::Node<store::MemoryStore, config::MockTime> = node::Node::new(s, cfg);
 
         for (input, expected) in test_cases {
-            // Necessary to implement Read trait on BufReader for bytes
             let mut vec: Vec<u8> = Vec::new();
-            let mut write_cursor = Cursor::new(&mut vec);
             let read_cursor = Cursor::new(input.as_bytes());
 
-            listen(&mut n, read_cursor, &mut write_cursor).expect("listen failed");
+            n.run(read_cursor, &mut vec, Unique)
+                .await
+                .expect("run failed");
 
             assert_eq!(String::from_utf8(vec).unwrap().trim(), expected.trim());
         }